@@ -0,0 +1,95 @@
+use rblock::raw::{DiffReport, diff};
+use std::env;
+use std::fs::File;
+use std::process;
+
+const DEFAULT_CLUSTER_SIZE: u64 = 65536;
+
+// Exit codes are the ones of `cmp`: 0 if the devices are identical, 1 if they
+// differ and 2 if they can't be compared.
+fn usage(progname: &str) -> ! {
+    eprintln!(
+        "Usage: {} [--granularity sector|4k|cluster] [--cluster-size BYTES] [--json] A B",
+        progname
+    );
+    process::exit(2);
+}
+
+fn print_text(report: &DiffReport) {
+    let granularity = report.granularity;
+    for range in &report.ranges {
+        let blocks = match range.count {
+            1 => range.first.to_string(),
+            n => format!("{}-{}", range.first, range.first + n - 1),
+        };
+        println!(
+            "{}: 0x{:x} +{}",
+            blocks,
+            range.first * granularity,
+            range.count * granularity
+        );
+    }
+
+    if report.size_a != report.size_b {
+        println!("sizes differ: {} and {}", report.size_a, report.size_b);
+    }
+    println!(
+        "{} of {} blocks of {} bytes differ",
+        report.changed_blocks, report.total_blocks, granularity
+    );
+}
+
+fn main() {
+    let mut arguments = env::args();
+    let progname = arguments.next().unwrap_or_else(|| "rb-diff".to_string());
+
+    let mut granularity = "sector".to_string();
+    let mut cluster_size = DEFAULT_CLUSTER_SIZE;
+    let mut json = false;
+    let mut files = Vec::new();
+
+    while let Some(arg) = arguments.next() {
+        match arg.as_str() {
+            "--granularity" => granularity = arguments.next().unwrap_or_else(|| usage(&progname)),
+            "--cluster-size" => match arguments.next().map(|n| n.parse()) {
+                Some(Ok(size)) => cluster_size = size,
+                _ => usage(&progname),
+            },
+            "--json" => json = true,
+            "-h" | "--help" => usage(&progname),
+            _ => files.push(arg),
+        }
+    }
+
+    let granularity = match granularity.as_str() {
+        "sector" | "512" => 512,
+        "4k" | "4096" => 4096,
+        "cluster" => cluster_size,
+        _ => usage(&progname),
+    };
+
+    let (a, b) = match &files[..] {
+        [a, b] => (a, b),
+        _ => usage(&progname),
+    };
+
+    let result = File::open(a)
+        .and_then(|fa| File::open(b).map(|fb| (fa, fb)))
+        .and_then(|(fa, fb)| diff(&fa, &fb, granularity));
+
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Failed to compare {} and {}: {}", a, b, e);
+            process::exit(2);
+        }
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        print_text(&report);
+    }
+
+    process::exit(if report.is_identical() { 0 } else { 1 });
+}
@@ -1,2 +1,5 @@
 pub mod qcow2;
+pub mod raw;
 pub mod server;
+#[cfg(test)]
+mod testutil;
@@ -1,21 +1,412 @@
-use rblock::server::start_servers;
+use rblock::qcow2::{CheckReport, CreateOptions, Qcow2, RepairMode};
+use rblock::server::{serve, start_servers};
 use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
 
-fn main() {
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Trace)
-        .init();
+fn usage(progname: &str) -> ! {
+    eprintln!("Usage: {} [--write] [FILE]", progname);
+    eprintln!("       {} --snapshot ID|NAME [FILE]", progname);
+    eprintln!("       {} check [--repair leaks|all] FILE", progname);
+    eprintln!(
+        "       {} convert [--cluster-bits N] [--version 2|3] RAW QCOW2",
+        progname
+    );
+    eprintln!("       {} convert -O raw QCOW2 RAW", progname);
+    eprintln!(
+        "       {} delta [--cluster-bits N] [--version 2|3] BASE MODIFIED QCOW2",
+        progname
+    );
+    eprintln!("       {} commit [--empty] QCOW2", progname);
+    eprintln!("       {} rebase [-u] -b BACKING [-F FMT] QCOW2", progname);
+    eprintln!("       {} resize [--shrink] QCOW2 SIZE[K|M|G|T]", progname);
+    eprintln!("       {} compact QCOW2", progname);
+    process::exit(1);
+}
+
+// Exit codes are the ones of `qemu-img check`: 0 if the image is clean, 1 if
+// the check failed, 2 if there are corruptions and 3 if there are only leaks.
+fn check(progname: &str, args: &[String]) -> i32 {
+    let mut repair = None;
+    let mut fname = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--repair" => match args.next().map(|mode| mode.parse::<RepairMode>()) {
+                Some(Ok(mode)) => repair = Some(mode),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    usage(progname);
+                }
+                None => usage(progname),
+            },
+            _ if fname.is_none() => fname = Some(arg.as_str()),
+            _ => usage(progname),
+        }
+    }
+
+    let fname = fname.unwrap_or_else(|| usage(progname));
+
+    let result = match repair {
+        None => Qcow2::new(fname).and_then(|q| q.check()),
+        Some(mode) => Qcow2::open_for_repair(fname).and_then(|mut q| q.repair(mode)),
+    };
+
+    let report: CheckReport = match result {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Failed to check {}: {}", fname, e);
+            return 1;
+        }
+    };
+
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+    if report.corruptions > 0 {
+        2
+    } else if report.leaks > 0 {
+        3
+    } else {
+        0
+    }
+}
+
+// Parses the value of an option of the created image
+fn parse_create_option<'a>(
+    progname: &str,
+    opts: &mut CreateOptions,
+    option: &str,
+    args: &mut impl Iterator<Item = &'a String>,
+) {
+    let value = match args.next().map(|n| n.parse()) {
+        Some(Ok(value)) => value,
+        _ => usage(progname),
+    };
+
+    match option {
+        "--cluster-bits" => opts.cluster_bits = value,
+        "--version" => opts.version = value,
+        _ => usage(progname),
+    }
+}
+
+// Returns a progress callback that prints the percentage when it changes
+fn print_progress<'a>(action: &'a str, src: &'a str, dst: &'a str) -> impl FnMut(u64, u64) + 'a {
+    let mut percent = None;
+    move |done, total| {
+        let now = (done * 100).checked_div(total).unwrap_or(100);
+        if percent != Some(now) {
+            percent = Some(now);
+            eprint!("\r{} {} to {}: {:3}%", action, src, dst, now);
+            let _ = io::stderr().flush();
+        }
+    }
+}
+
+fn convert(progname: &str, args: &[String]) -> i32 {
+    let mut opts = CreateOptions::default();
+    let mut to_raw = false;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cluster-bits" | "--version" => {
+                parse_create_option(progname, &mut opts, arg, &mut args)
+            }
+            "-O" => match args.next().map(String::as_str) {
+                Some("raw") => to_raw = true,
+                Some("qcow2") => to_raw = false,
+                _ => usage(progname),
+            },
+            _ => files.push(arg.as_str()),
+        }
+    }
+
+    let (src, dst) = match files[..] {
+        [src, dst] => (src, dst),
+        _ => usage(progname),
+    };
+
+    let progress = print_progress("Converting", src, dst);
+
+    // The whole backing chain is flattened into the raw output
+    let result = if to_raw {
+        Qcow2::new(src).and_then(|q| q.convert_to_raw(dst, progress))
+    } else {
+        Qcow2::convert_from_raw(src, dst, opts, progress).map(|_| ())
+    };
+    eprintln!();
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Failed to convert {} to {}: {}", src, dst, e);
+            1
+        }
+    }
+}
+
+fn delta(progname: &str, args: &[String]) -> i32 {
+    let mut opts = CreateOptions::default();
+    let mut files = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cluster-bits" | "--version" => {
+                parse_create_option(progname, &mut opts, arg, &mut args)
+            }
+            _ => files.push(arg.as_str()),
+        }
+    }
+
+    let (base, modified, dst) = match files[..] {
+        [base, modified, dst] => (base, modified, dst),
+        _ => usage(progname),
+    };
+
+    let progress = print_progress("Writing delta of", modified, dst);
+    let result = Qcow2::create_delta(base, modified, dst, opts, progress);
+    eprintln!();
+
+    match result {
+        Ok(q) => {
+            println!(
+                "{} has backing file {}",
+                dst,
+                q.backing_file().unwrap_or_default()
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to write delta of {} to {}: {}", modified, dst, e);
+            1
+        }
+    }
+}
 
+fn commit(progname: &str, args: &[String]) -> i32 {
+    let (empty, fname) = match args {
+        [fname] => (false, fname),
+        [flag, fname] if flag == "--empty" => (true, fname),
+        _ => usage(progname),
+    };
+
+    let mut q = match Qcow2::open(fname, empty) {
+        Ok(q) => q,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", fname, e);
+            return 1;
+        }
+    };
+
+    let backing = q.backing_file().unwrap_or_default();
+    let progress = print_progress("Committing", fname, &backing);
+    let result = q.commit(empty, progress);
+    eprintln!();
+
+    match result {
+        Ok(clusters) => {
+            println!("{} cluster(s) committed into {}", clusters, backing);
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to commit {}: {}", fname, e);
+            1
+        }
+    }
+}
+
+fn rebase(progname: &str, args: &[String]) -> i32 {
+    let mut safe = true;
+    let mut backing = None;
+    let mut backing_fmt = None;
+    let mut fname = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-u" => safe = false,
+            "-b" => backing = Some(args.next().unwrap_or_else(|| usage(progname))),
+            "-F" => backing_fmt = Some(args.next().unwrap_or_else(|| usage(progname))),
+            _ if fname.is_none() => fname = Some(arg.as_str()),
+            _ => usage(progname),
+        }
+    }
+
+    // An empty backing file name removes the backing file
+    let backing = match backing {
+        Some(name) if name.is_empty() => None,
+        Some(name) => Some(name.as_str()),
+        None => usage(progname),
+    };
+    let fname = fname.unwrap_or_else(|| usage(progname));
+
+    // The current backing file is not needed for an unsafe rebase, it may
+    // not even exist anymore.
+    let result = if safe {
+        Qcow2::open(fname, true)
+    } else {
+        Qcow2::open_without_backing(fname, true)
+    };
+    let mut q = match result {
+        Ok(q) => q,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", fname, e);
+            return 1;
+        }
+    };
+
+    let progress = print_progress("Rebasing", fname, backing.unwrap_or("no backing file"));
+    let result = q.rebase(backing, backing_fmt.map(String::as_str), safe, progress);
+    if safe {
+        eprintln!();
+    }
+
+    match result {
+        Ok(clusters) => {
+            println!("{} cluster(s) copied into {}", clusters, fname);
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to rebase {}: {}", fname, e);
+            1
+        }
+    }
+}
+
+// Parses a size in bytes with an optional K, M, G or T suffix
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, shift) = match size.char_indices().last()? {
+        (i, 'K' | 'k') => (&size[..i], 10),
+        (i, 'M' | 'm') => (&size[..i], 20),
+        (i, 'G' | 'g') => (&size[..i], 30),
+        (i, 'T' | 't') => (&size[..i], 40),
+        _ => (size, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn resize(progname: &str, args: &[String]) -> i32 {
+    let (shrink, fname, size) = match args {
+        [fname, size] => (false, fname, size),
+        [flag, fname, size] if flag == "--shrink" => (true, fname, size),
+        _ => usage(progname),
+    };
+    let size = parse_size(size).unwrap_or_else(|| usage(progname));
+
+    let result = Qcow2::open(fname, true).and_then(|mut q| q.resize(size, shrink));
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Failed to resize {} to {}: {}", fname, size, e);
+            1
+        }
+    }
+}
+
+fn compact(progname: &str, args: &[String]) -> i32 {
+    let fname = match args {
+        [fname] => fname,
+        _ => usage(progname),
+    };
+
+    let file_len = || fs::metadata(fname).map(|m| m.len());
+    let result = file_len().and_then(|before| {
+        let clusters = Qcow2::open(fname, true)?.compact()?;
+        Ok((clusters, before, file_len()?))
+    });
+
+    match result {
+        Ok((clusters, before, after)) => {
+            println!(
+                "{} cluster(s) moved, {} shrunk from {} to {} bytes",
+                clusters, fname, before, after
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to compact {}: {}", fname, e);
+            1
+        }
+    }
+}
+
+fn main() {
     const QCOWFNAME: &str = "samples/disk.qcow2";
     let mut arguments = env::args();
 
     // Skip the first argument that is the name of program
-    let _progname = arguments.next();
+    let progname = arguments.next().unwrap_or_else(|| "rblock".to_string());
+    let args: Vec<String> = arguments.collect();
 
-    let fname = match arguments.next() {
-        None => QCOWFNAME.to_string(),
-        Some(f) => f,
+    // Subcommands only log warnings by default to keep the output readable
+    let command = args.first().map(String::as_str);
+    let level = match command {
+        Some("check" | "convert" | "delta" | "commit" | "rebase" | "resize" | "compact") => {
+            log::LevelFilter::Warn
+        }
+        _ => log::LevelFilter::Trace,
     };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .init();
+
+    match command {
+        Some("check") => process::exit(check(&progname, &args[1..])),
+        Some("convert") => process::exit(convert(&progname, &args[1..])),
+        Some("delta") => process::exit(delta(&progname, &args[1..])),
+        Some("commit") => process::exit(commit(&progname, &args[1..])),
+        Some("rebase") => process::exit(rebase(&progname, &args[1..])),
+        Some("resize") => process::exit(resize(&progname, &args[1..])),
+        Some("compact") => process::exit(compact(&progname, &args[1..])),
+        Some("-h") | Some("--help") => usage(&progname),
+        // Serve a read-only view of the image at an internal snapshot
+        Some("--snapshot") => {
+            let snapshot = args.get(1).unwrap_or_else(|| usage(&progname));
+            let fname = args.get(2).map(String::as_str).unwrap_or(QCOWFNAME);
+            match Qcow2::open_snapshot(fname, snapshot) {
+                Ok(q) => serve(q),
+                Err(e) => {
+                    eprintln!("Failed to open snapshot {} of {}: {}", snapshot, fname, e);
+                    process::exit(1);
+                }
+            }
+        }
+        // The image is served read-only unless writes are asked for
+        Some("--write") => {
+            start_servers(args.get(1).map(String::as_str).unwrap_or(QCOWFNAME), true)
+        }
+        Some(fname) => start_servers(fname, false),
+        None => start_servers(QCOWFNAME, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_size;
+
+    #[test]
+    fn sizes_with_suffixes() {
+        assert_eq!(parse_size("0"), Some(0));
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("4k"), Some(4 << 10));
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+        assert_eq!(parse_size("20G"), Some(20 << 30));
+        assert_eq!(parse_size("2t"), Some(2 << 40));
+    }
 
-    start_servers(&fname);
+    #[test]
+    fn invalid_sizes() {
+        for size in ["", "G", "-1", "1.5G", "10X", "1GB", " 1G"] {
+            assert_eq!(parse_size(size), None, "{:?}", size);
+        }
+        // Overflows
+        assert_eq!(parse_size("18446744073709551616"), None);
+        assert_eq!(parse_size("16777216T"), None);
+    }
 }
@@ -0,0 +1,220 @@
+use log::debug;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use super::{QCOW2_MAGIC, Qcow2};
+use crate::raw::device_size;
+
+// Backing files are opened read-only and can be either another qcow2 image
+// (that can have its own backing file) or a raw image.
+pub(super) enum Backing {
+    Raw(File),
+    Qcow2(Box<Qcow2>),
+}
+
+// The backing file name is relative to the directory of the overlay unless
+// it is an absolute path. The same goes for the external data file.
+pub(super) fn resolve_path(overlay: &Path, backing_name: &str) -> PathBuf {
+    let backing = Path::new(backing_name);
+    if backing.is_absolute() {
+        return backing.to_path_buf();
+    }
+
+    match overlay.parent() {
+        Some(dir) => dir.join(backing),
+        None => backing.to_path_buf(),
+    }
+}
+
+impl Backing {
+    // `chain` holds the canonical path of all images already opened above
+    // this backing file. When the overlay doesn't give the format of the
+    // backing file we guess it.
+    pub(super) fn open(
+        overlay: &Path,
+        backing_name: &str,
+        backing_fmt: Option<&str>,
+        chain: &mut Vec<PathBuf>,
+    ) -> io::Result<Self> {
+        let path = resolve_path(overlay, backing_name);
+        debug!("Opening backing file {:?}", path);
+
+        let file = File::open(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to open backing file {:?}: {}", path, e),
+            )
+        })?;
+
+        let is_qcow2 = match backing_fmt {
+            Some("qcow2") => true,
+            Some("raw") => false,
+            Some(fmt) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Backing file {:?} has unsupported format {}", path, fmt),
+                ));
+            }
+            None => {
+                let mut magic: [u8; 4] = [0u8; 4];
+                file.read_exact_at(&mut magic, 0).is_ok()
+                    && u32::from_be_bytes(magic) == QCOW2_MAGIC
+            }
+        };
+
+        if is_qcow2 {
+            let q = Qcow2::open_chain(&path, false, Some(chain), false)?;
+            Ok(Backing::Qcow2(Box::new(q)))
+        } else {
+            Ok(Backing::Raw(file))
+        }
+    }
+
+    // Fills `buf` with the data at guest `offset`. Bytes beyond the end of
+    // the backing image are read as zeros.
+    pub(super) fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        buf.fill(0);
+
+        match self {
+            Backing::Raw(file) => {
+                let mut done = 0;
+                while done < buf.len() {
+                    match file.read_at(&mut buf[done..], offset + done as u64)? {
+                        0 => break,
+                        n => done += n,
+                    }
+                }
+            }
+            Backing::Qcow2(q) => {
+                let data = q.read_at(offset, buf.len())?;
+                buf[..data.len()].copy_from_slice(&data);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns true when the `len` bytes at guest `offset` are known to read
+    // as zeros without reading them, it is the case beyond the end of the
+    // backing image.
+    pub(super) fn reads_as_zeros(&self, offset: u64, len: u64) -> io::Result<bool> {
+        match self {
+            Backing::Raw(file) => Ok(offset >= device_size(file)?),
+            Backing::Qcow2(q) => q.range_reads_as_zeros(offset, len),
+        }
+    }
+
+    // Opens the same backing file at `path` read-write, to commit the data of
+    // the overlay into it.
+    pub(super) fn reopen_writable(&self, path: &Path) -> io::Result<Self> {
+        match self {
+            Backing::Raw(_) => Ok(Backing::Raw(
+                OpenOptions::new().read(true).write(true).open(path)?,
+            )),
+            Backing::Qcow2(_) => {
+                let q = Qcow2::open_chain(path, true, Some(&mut Vec::new()), false)?;
+                Ok(Backing::Qcow2(Box::new(q)))
+            }
+        }
+    }
+
+    pub(super) fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        match self {
+            Backing::Raw(file) => file.write_all_at(buf, offset),
+            Backing::Qcow2(q) => q.write_at(offset, buf),
+        }
+    }
+
+    pub(super) fn size(&self) -> io::Result<u64> {
+        match self {
+            Backing::Raw(file) => device_size(file),
+            Backing::Qcow2(q) => Ok(q.virtual_size()),
+        }
+    }
+
+    pub(super) fn resize(&mut self, size: u64) -> io::Result<()> {
+        match self {
+            Backing::Raw(file) => file.set_len(size),
+            Backing::Qcow2(q) => q.resize(size, false),
+        }
+    }
+
+    pub(super) fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Backing::Raw(file) => file.sync_data(),
+            Backing::Qcow2(q) => q.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::super::{CreateOptions, Qcow2};
+    use crate::testutil::TempFile;
+
+    // Name of the file relative to the directory of the overlay
+    fn file_name(tmp: &TempFile) -> String {
+        let name = Path::new(tmp.path()).file_name().unwrap();
+        name.to_str().unwrap().to_string()
+    }
+
+    fn create_overlay(tmp: &TempFile, backing: &TempFile, fmt: &str) -> Qcow2 {
+        let opts = CreateOptions {
+            backing_file: Some(file_name(backing)),
+            backing_fmt: Some(fmt.to_string()),
+            ..Default::default()
+        };
+        Qcow2::create(tmp.path(), opts).unwrap()
+    }
+
+    #[test]
+    fn unallocated_clusters_read_from_relative_backing_file() {
+        let raw = TempFile::new("backing-raw");
+        let base: Vec<u8> = (0..1 << 18).map(|i| (i % 253) as u8 + 1).collect();
+        fs::write(raw.path(), &base).unwrap();
+
+        let tmp = TempFile::new("backing-overlay");
+        let mut q = create_overlay(&tmp, &raw, "raw");
+        assert_eq!(q.virtual_size(), 1 << 18);
+        q.write_at(70_000, &[0xaa; 1000]).unwrap();
+        drop(q);
+
+        let q = Qcow2::new(tmp.path()).unwrap();
+        let mut expected = base.clone();
+        expected[70_000..71_000].fill(0xaa);
+        assert!(q.read_at(0, 1 << 18).unwrap() == expected);
+        assert_eq!(q.read_guest_cluster(2).unwrap(), base[2 << 16..3 << 16]);
+    }
+
+    #[test]
+    fn backing_chain_loop_is_detected() {
+        let a = TempFile::new("backing-loop-a");
+        let opts = CreateOptions {
+            virtual_size: 1 << 20,
+            ..Default::default()
+        };
+        drop(Qcow2::create(a.path(), opts).unwrap());
+
+        let b = TempFile::new("backing-loop-b");
+        drop(create_overlay(&b, &a, "qcow2"));
+        assert!(Qcow2::new(b.path()).is_ok());
+
+        // The new backing file can't be opened so only the header changes
+        let mut q = Qcow2::open(a.path(), true).unwrap();
+        q.rebase(Some(&file_name(&b)), Some("qcow2"), false, |_, _| {})
+            .unwrap();
+        drop(q);
+
+        for tmp in [&a, &b] {
+            let e = Qcow2::new(tmp.path()).err().unwrap();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+            assert!(e.to_string().contains("Loop detected"), "{}", e);
+        }
+        assert!(Qcow2::open_without_backing(a.path(), false).is_ok());
+    }
+}
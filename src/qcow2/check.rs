@@ -0,0 +1,874 @@
+use log::debug;
+use serde::Serialize;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::str::FromStr;
+
+use super::entry::{L1E_OFFSET_MASK, L2E_OFFSET_MASK};
+use super::refcount::{REFT_OFFSET_MASK, read_refcount};
+use super::{HeaderExtension, INCOMPAT_DIRTY, L1Entry, L2Entry, Qcow2};
+
+// Bits 9-55 of a bitmap table entry are the offset of the data cluster
+const BME_OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+
+// Fixed part of the entries of the bitmap directory. It is followed by
+// variable length data and padded to 8 bytes.
+const BITMAP_HEADER_SIZE: u64 = 24;
+
+/// What a host cluster is used for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum ClusterUse {
+    Header,
+    L1Table,
+    L2Table,
+    Data,
+    CompressedData,
+    RefcountTable,
+    RefcountBlock,
+    SnapshotTable,
+    BitmapDirectory,
+    BitmapTable,
+    BitmapData,
+    Encryption,
+}
+
+impl ClusterUse {
+    // L2 tables and data clusters are shared with snapshots and several
+    // compressed clusters can be stored in the same host cluster. Any other
+    // metadata must only be referenced once.
+    fn is_shareable(self) -> bool {
+        matches!(
+            self,
+            ClusterUse::L2Table | ClusterUse::Data | ClusterUse::CompressedData
+        )
+    }
+}
+
+/// A problem found by [`Qcow2::check`]. `location` describes the entry that
+/// holds the faulty offset.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum CheckIssue {
+    /// The cluster has a refcount but nothing references it.
+    Leak {
+        cluster: u64,
+        refcount: u64,
+    },
+    /// The refcount doesn't match the number of references to the cluster.
+    RefcountMismatch {
+        cluster: u64,
+        refcount: u64,
+        references: u64,
+    },
+    /// The cluster is used by two structures that can't share it.
+    Overlap {
+        cluster: u64,
+        first: ClusterUse,
+        second: ClusterUse,
+    },
+    Misaligned {
+        location: String,
+        offset: u64,
+    },
+    OutOfBounds {
+        location: String,
+        offset: u64,
+    },
+    InvalidEntry {
+        location: String,
+        error: String,
+    },
+    /// The COPIED flag is set while the refcount is not 1, or the other way
+    /// around.
+    CopiedFlag {
+        location: String,
+        refcount: u64,
+    },
+}
+
+impl CheckIssue {
+    /// Leaks only waste space, all other issues can corrupt data.
+    pub fn is_leak(&self) -> bool {
+        match *self {
+            CheckIssue::Leak { .. } => true,
+            CheckIssue::RefcountMismatch {
+                refcount,
+                references,
+                ..
+            } => refcount > references,
+            _ => false,
+        }
+    }
+}
+
+/// Result of a consistency check of the image metadata.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CheckReport {
+    pub issues: Vec<CheckIssue>,
+    pub leaks: u64,
+    pub corruptions: u64,
+    /// Number of guest clusters in the virtual disk
+    pub total_clusters: u64,
+    /// Guest clusters that have data in the active L1 table
+    pub allocated_clusters: u64,
+    pub compressed_clusters: u64,
+    /// End of the last host cluster in use
+    pub image_end_offset: u64,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    // True if a stored refcount doesn't match the references to its cluster
+    fn has_refcount_errors(&self) -> bool {
+        self.issues.iter().any(|issue| {
+            matches!(
+                issue,
+                CheckIssue::Leak { .. } | CheckIssue::RefcountMismatch { .. }
+            )
+        })
+    }
+}
+
+/// What [`Qcow2::repair`] is allowed to fix, like `qemu-img check -r`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RepairMode {
+    /// Only lower the refcounts that are too high. It is always safe.
+    Leaks,
+    /// Also raise the refcounts that are too low and fix the COPIED flags.
+    All,
+}
+
+impl FromStr for RepairMode {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "leaks" => Ok(RepairMode::Leaks),
+            "all" => Ok(RepairMode::All),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown repair mode {}", s),
+            )),
+        }
+    }
+}
+
+// Walks all the metadata of the image and counts the references to each host
+// cluster.
+struct Checker<'a> {
+    q: &'a Qcow2,
+    file_len: u64,
+    // Length of the file that holds the data clusters
+    data_len: u64,
+    // Refcounts stored in the refcount blocks
+    refcounts: Vec<u64>,
+    // References found while walking the metadata
+    references: Vec<u64>,
+    uses: Vec<Option<ClusterUse>>,
+    report: CheckReport,
+}
+
+impl<'a> Checker<'a> {
+    fn new(q: &'a Qcow2) -> io::Result<Self> {
+        let cluster_sz = q.cluster_size() as u64;
+        Ok(Checker {
+            q,
+            file_len: q.file.metadata()?.len(),
+            data_len: q.data_file().metadata()?.len(),
+            refcounts: Vec::new(),
+            references: Vec::new(),
+            uses: Vec::new(),
+            report: CheckReport {
+                total_clusters: q.header.size.div_ceil(cluster_sz),
+                ..Default::default()
+            },
+        })
+    }
+
+    fn issue(&mut self, issue: CheckIssue) {
+        debug!("Check: {:?}", issue);
+        if issue.is_leak() {
+            self.report.leaks += 1;
+        } else {
+            self.report.corruptions += 1;
+        }
+        self.report.issues.push(issue);
+    }
+
+    fn refcount(&self, cluster: u64) -> u64 {
+        self.refcounts.get(cluster as usize).copied().unwrap_or(0)
+    }
+
+    // Counts a reference to every host cluster of the `len` bytes at `offset`.
+    // Returns false if the range can't be used because it is misaligned or
+    // beyond the end of the file.
+    fn reference(&mut self, location: &str, offset: u64, len: u64, usage: ClusterUse) -> bool {
+        let cluster_bits = self.q.cluster_bits();
+
+        if usage != ClusterUse::CompressedData && offset & ((1 << cluster_bits) - 1) != 0 {
+            self.issue(CheckIssue::Misaligned {
+                location: location.to_string(),
+                offset,
+            });
+            return false;
+        }
+
+        // Data clusters can be truncated at the end of the file but metadata
+        // must be fully readable.
+        let out_of_bounds = match usage {
+            ClusterUse::Data => offset >= self.data_len,
+            ClusterUse::CompressedData | ClusterUse::BitmapData => offset >= self.file_len,
+            _ => offset.saturating_add(len) > self.file_len,
+        };
+        if out_of_bounds {
+            self.issue(CheckIssue::OutOfBounds {
+                location: location.to_string(),
+                offset,
+            });
+            return false;
+        }
+
+        let first = offset >> cluster_bits;
+        let last = (offset + len.max(1) - 1) >> cluster_bits;
+        if last as usize >= self.references.len() {
+            self.references.resize(last as usize + 1, 0);
+            self.uses.resize(last as usize + 1, None);
+        }
+
+        for cluster in first..=last {
+            self.references[cluster as usize] += 1;
+            match self.uses[cluster as usize] {
+                None => self.uses[cluster as usize] = Some(usage),
+                Some(first) if first == usage && usage.is_shareable() => {}
+                Some(first) => self.issue(CheckIssue::Overlap {
+                    cluster,
+                    first,
+                    second: usage,
+                }),
+            }
+        }
+
+        true
+    }
+
+    fn read(&mut self, location: &str, offset: u64, len: u64) -> Option<Vec<u8>> {
+        let mut buf = vec![0u8; len as usize];
+        match self.q.file.read_exact_at(&mut buf, offset) {
+            Ok(()) => Some(buf),
+            Err(e) => {
+                self.issue(CheckIssue::InvalidEntry {
+                    location: location.to_string(),
+                    error: e.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    // Loads the refcounts stored on disk and references the refcount table
+    // and blocks.
+    fn check_refcount_structures(&mut self) {
+        let cluster_sz = self.q.cluster_size() as u64;
+        let table_offset = self.q.refcount_table_offset();
+        let table_len = self.q.refcount_table_clusters() * cluster_sz;
+        let order = self.q.refcount_order();
+        let block_entries = self.q.refcount_block_entries();
+
+        if !self.reference(
+            "refcount table",
+            table_offset,
+            table_len,
+            ClusterUse::RefcountTable,
+        ) {
+            return;
+        }
+
+        let table = match self.read("refcount table", table_offset, table_len) {
+            Some(table) => table,
+            None => return,
+        };
+
+        for (i, chunk) in table.chunks_exact(8).enumerate() {
+            let raw = u64::from_be_bytes(chunk.try_into().unwrap());
+            let block_offset = raw & REFT_OFFSET_MASK;
+            if block_offset == 0 {
+                continue;
+            }
+
+            let location = format!("refcount table[{}]", i);
+            if !self.reference(
+                &location,
+                block_offset,
+                cluster_sz,
+                ClusterUse::RefcountBlock,
+            ) {
+                continue;
+            }
+
+            let block = match self.read(&location, block_offset, cluster_sz) {
+                Some(block) => block,
+                None => continue,
+            };
+
+            let first = i as u64 * block_entries;
+            self.refcounts.resize((first + block_entries) as usize, 0);
+            for index in 0..block_entries {
+                self.refcounts[(first + index) as usize] = read_refcount(&block, index, order);
+            }
+        }
+    }
+
+    // Walks an L1 table and the L2 tables it points to. The COPIED flags are
+    // only meaningful for the active L1 table.
+    fn check_l1_table(&mut self, name: &str, l1_offset: u64, l1_size: u64, active: bool) {
+        if l1_size == 0 {
+            return;
+        }
+
+        let location = format!("{} L1 table", name);
+        if !self.reference(&location, l1_offset, l1_size * 8, ClusterUse::L1Table) {
+            return;
+        }
+
+        let table = match self.read(&location, l1_offset, l1_size * 8) {
+            Some(table) => table,
+            None => return,
+        };
+
+        let cluster_sz = self.q.cluster_size() as u64;
+        let cluster_bits = self.q.cluster_bits();
+
+        for (i, chunk) in table.chunks_exact(8).enumerate() {
+            let raw = u64::from_be_bytes(chunk.try_into().unwrap());
+            if raw == 0 {
+                continue;
+            }
+
+            let location = format!("{} L1[{}]", name, i);
+            let (l2_offset, copied) = match L1Entry::parse(raw, cluster_sz) {
+                Ok(L1Entry::Unallocated) => continue,
+                Ok(L1Entry::L2Table { offset, copied }) => (offset, copied),
+                // An unaligned offset is reported by reference()
+                Err(_) if raw & L1E_OFFSET_MASK & (cluster_sz - 1) != 0 => {
+                    self.reference(
+                        &location,
+                        raw & L1E_OFFSET_MASK,
+                        cluster_sz,
+                        ClusterUse::L2Table,
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    self.issue(CheckIssue::InvalidEntry {
+                        location,
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            if !self.reference(&location, l2_offset, cluster_sz, ClusterUse::L2Table) {
+                continue;
+            }
+
+            if active {
+                let refcount = self.refcount(l2_offset >> cluster_bits);
+                if copied != (refcount == 1) {
+                    self.issue(CheckIssue::CopiedFlag {
+                        location: location.clone(),
+                        refcount,
+                    });
+                }
+            }
+
+            self.check_l2_table(name, l2_offset, active);
+        }
+    }
+
+    fn check_l2_table(&mut self, name: &str, l2_offset: u64, active: bool) {
+        let cluster_sz = self.q.cluster_size() as u64;
+        let cluster_bits = self.q.cluster_bits();
+        let version = self.q.version();
+        let extended = self.q.is_extended_l2();
+
+        let location = format!("{} L2 table 0x{:x}", name, l2_offset);
+        let table = match self.read(&location, l2_offset, cluster_sz) {
+            Some(table) => table,
+            None => return,
+        };
+
+        for (j, chunk) in table
+            .chunks_exact(self.q.l2_entry_size() as usize)
+            .enumerate()
+        {
+            let raw = u64::from_be_bytes(chunk[..8].try_into().unwrap());
+            if chunk.iter().all(|&b| b == 0) {
+                continue;
+            }
+
+            let location = format!("{}[{}]", location, j);
+            let parsed = if extended {
+                let bitmap = u64::from_be_bytes(chunk[8..16].try_into().unwrap());
+                L2Entry::parse_extended(raw, bitmap, cluster_bits).map(|(entry, _)| entry)
+            } else {
+                L2Entry::parse(raw, cluster_bits, version)
+            };
+            let entry = match parsed {
+                Ok(entry) => entry,
+                Err(_) if raw & L2E_OFFSET_MASK & (cluster_sz - 1) != 0 => {
+                    self.reference(
+                        &location,
+                        raw & L2E_OFFSET_MASK,
+                        cluster_sz,
+                        ClusterUse::Data,
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    self.issue(CheckIssue::InvalidEntry {
+                        location,
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            match entry {
+                L2Entry::Unallocated | L2Entry::Zero => {}
+                L2Entry::Normal {
+                    host_offset,
+                    copied,
+                }
+                | L2Entry::ZeroPreallocated {
+                    host_offset,
+                    copied,
+                } => {
+                    if !self.reference(&location, host_offset, cluster_sz, ClusterUse::Data) {
+                        continue;
+                    }
+
+                    if active {
+                        if matches!(entry, L2Entry::Normal { .. }) {
+                            self.report.allocated_clusters += 1;
+                        }
+
+                        let refcount = self.refcount(host_offset >> cluster_bits);
+                        if copied != (refcount == 1) {
+                            self.issue(CheckIssue::CopiedFlag { location, refcount });
+                        }
+                    }
+                }
+                L2Entry::Compressed { host_offset, size } => {
+                    if self.reference(&location, host_offset, size, ClusterUse::CompressedData)
+                        && active
+                    {
+                        self.report.allocated_clusters += 1;
+                        self.report.compressed_clusters += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    // Walks the L1 tables of the snapshots
+    fn check_snapshots(&mut self) {
+        if self.q.nb_snapshots() == 0 {
+            return;
+        }
+
+        let snapshots = match self.q.snapshots() {
+            Ok(snapshots) => snapshots,
+            Err(e) => {
+                self.issue(CheckIssue::InvalidEntry {
+                    location: "snapshot table".to_string(),
+                    error: e.to_string(),
+                });
+                return;
+            }
+        };
+
+        let table_len = snapshots.iter().map(|s| s.size()).sum();
+        if !self.reference(
+            "snapshot table",
+            self.q.snapshots_offset(),
+            table_len,
+            ClusterUse::SnapshotTable,
+        ) {
+            return;
+        }
+
+        for snapshot in snapshots {
+            self.check_l1_table(
+                &format!("snapshot {}", snapshot.id),
+                snapshot.l1_table_offset,
+                snapshot.l1_size as u64,
+                false,
+            );
+        }
+    }
+
+    // Walks the bitmap directory, the bitmap tables and their data clusters
+    fn check_bitmaps(&mut self, dir_offset: u64, dir_size: u64, nb_bitmaps: u32) {
+        if !self.reference(
+            "bitmap directory",
+            dir_offset,
+            dir_size,
+            ClusterUse::BitmapDirectory,
+        ) {
+            return;
+        }
+
+        let dir = match self.read("bitmap directory", dir_offset, dir_size) {
+            Some(dir) => dir,
+            None => return,
+        };
+
+        let cluster_sz = self.q.cluster_size() as u64;
+        let mut pos = 0;
+
+        for i in 0..nb_bitmaps {
+            let location = format!("bitmap directory[{}]", i);
+            if pos + BITMAP_HEADER_SIZE > dir_size {
+                self.issue(CheckIssue::InvalidEntry {
+                    location,
+                    error: "Entry goes beyond the bitmap directory".to_string(),
+                });
+                return;
+            }
+
+            let entry = &dir[pos as usize..];
+            let table_offset = u64::from_be_bytes(entry[0..8].try_into().unwrap());
+            let table_size = u32::from_be_bytes(entry[8..12].try_into().unwrap()) as u64;
+            let name_len = u16::from_be_bytes(entry[18..20].try_into().unwrap()) as u64;
+            let extra_len = u32::from_be_bytes(entry[20..24].try_into().unwrap()) as u64;
+            pos += (BITMAP_HEADER_SIZE + extra_len + name_len).div_ceil(8) * 8;
+
+            if !self.reference(
+                &location,
+                table_offset,
+                table_size * 8,
+                ClusterUse::BitmapTable,
+            ) {
+                continue;
+            }
+
+            let table = match self.read(&location, table_offset, table_size * 8) {
+                Some(table) => table,
+                None => continue,
+            };
+
+            for (j, chunk) in table.chunks_exact(8).enumerate() {
+                let data_offset = u64::from_be_bytes(chunk.try_into().unwrap()) & BME_OFFSET_MASK;
+                if data_offset != 0 {
+                    let location = format!("bitmap table 0x{:x}[{}]", table_offset, j);
+                    self.reference(&location, data_offset, cluster_sz, ClusterUse::BitmapData);
+                }
+            }
+        }
+    }
+
+    // Compares the stored refcounts with the references that were found
+    fn check_refcounts(&mut self) {
+        let cluster_bits = self.q.cluster_bits();
+        let nb_clusters = self.refcounts.len().max(self.references.len());
+
+        for cluster in 0..nb_clusters as u64 {
+            let refcount = self.refcount(cluster);
+            let references = self.references.get(cluster as usize).copied().unwrap_or(0);
+
+            if references > 0 {
+                self.report.image_end_offset = (cluster + 1) << cluster_bits;
+            }
+
+            if refcount == references {
+                continue;
+            }
+
+            if references == 0 {
+                self.issue(CheckIssue::Leak { cluster, refcount });
+            } else {
+                self.issue(CheckIssue::RefcountMismatch {
+                    cluster,
+                    refcount,
+                    references,
+                });
+            }
+        }
+    }
+
+    fn run(mut self) -> CheckReport {
+        let q = self.q;
+
+        // The first cluster holds the header, its extensions and the backing
+        // file name.
+        self.reference("header", 0, q.header_len(), ClusterUse::Header);
+
+        self.check_refcount_structures();
+        // The header is used directly as the image can be a view at a
+        // snapshot.
+        let header = &q.header;
+        self.check_l1_table(
+            "active",
+            header.l1_table_offset,
+            header.l1_size as u64,
+            true,
+        );
+        self.check_snapshots();
+
+        for ext in q.header_extensions() {
+            match *ext {
+                HeaderExtension::Bitmaps {
+                    nb_bitmaps,
+                    bitmap_directory_size,
+                    bitmap_directory_offset,
+                    ..
+                } => self.check_bitmaps(bitmap_directory_offset, bitmap_directory_size, nb_bitmaps),
+                HeaderExtension::FullDiskEncryption { offset, length } => {
+                    self.reference("encryption header", offset, length, ClusterUse::Encryption);
+                }
+                _ => {}
+            }
+        }
+
+        self.check_refcounts();
+        self.report
+    }
+}
+
+impl Qcow2 {
+    /// Checks the consistency of the image metadata like `qemu-img check`.
+    /// It walks the L1 and L2 tables of the image and of its snapshots,
+    /// recomputes the refcount of every host cluster and compares it with the
+    /// stored one. Problems are returned in the report, an error is only
+    /// returned if the image can't be read.
+    pub fn check(&self) -> io::Result<CheckReport> {
+        let report = Checker::new(self)?.run();
+        debug!(
+            "Check done: {} leak(s), {} corruption(s)",
+            report.leaks, report.corruptions
+        );
+        Ok(report)
+    }
+
+    /// Rewrites the refcounts that don't match the references found by
+    /// [`Qcow2::check`] and clears the dirty bit once they are all right.
+    /// Returns the report of a check done after the repair.
+    pub fn repair(&mut self, mode: RepairMode) -> io::Result<CheckReport> {
+        self.check_writable()?;
+
+        let report = self.check()?;
+        debug!("Repairing {:?}", mode);
+
+        // Some clusters in use may have a refcount of 0 until they are fixed
+        // so the refcount blocks we allocate meanwhile go after the end of
+        // the image.
+        if mode == RepairMode::All {
+            self.free_cluster_index = report.image_end_offset >> self.cluster_bits();
+
+            for issue in &report.issues {
+                if let CheckIssue::RefcountMismatch {
+                    cluster,
+                    refcount,
+                    references,
+                } = *issue
+                    && refcount < references
+                {
+                    self.set_refcount(cluster, references)?;
+                }
+            }
+        }
+
+        for issue in &report.issues {
+            match *issue {
+                CheckIssue::Leak { cluster, .. } => self.set_refcount(cluster, 0)?,
+                CheckIssue::RefcountMismatch {
+                    cluster,
+                    refcount,
+                    references,
+                } if refcount > references => self.set_refcount(cluster, references)?,
+                _ => {}
+            }
+        }
+
+        if mode == RepairMode::All {
+            self.update_copied_flags()?;
+        }
+
+        self.free_cluster_index = 0;
+        self.flush()?;
+
+        let report = self.check()?;
+        if self.is_dirty() && !report.has_refcount_errors() {
+            self.header.incompatible_features &= !INCOMPAT_DIRTY;
+            self.write_header()?;
+            self.flush()?;
+            debug!("Dirty bit cleared");
+        }
+
+        Ok(report)
+    }
+
+    // Sets the COPIED flag of the entries of the active tables if and only if
+    // their refcount is 1. Entries that can't be parsed are left as is.
+    pub(super) fn update_copied_flags(&mut self) -> io::Result<()> {
+        let cluster_bits = self.cluster_bits();
+
+        for l1_index in 0..self.l1_size() {
+            let (l2_offset, copied) = match self.l1_entry(l1_index) {
+                Ok(L1Entry::L2Table { offset, copied }) => (offset, copied),
+                _ => continue,
+            };
+
+            let refcount = self.get_refcount(l2_offset >> cluster_bits)?;
+            if copied != (refcount == 1) {
+                let entry = L1Entry::L2Table {
+                    offset: l2_offset,
+                    copied: refcount == 1,
+                };
+                self.write_entry(self.l1_table_offset() + l1_index * 8, entry.to_raw())?;
+            }
+
+            for l2_index in 0..self.l2_entries() {
+                let entry_offset = l2_offset + l2_index * self.l2_entry_size();
+                let entry = match self.read_l2_entry(entry_offset) {
+                    Ok((entry, _)) => entry,
+                    Err(_) => continue,
+                };
+
+                let fixed = match entry {
+                    L2Entry::Normal {
+                        host_offset,
+                        copied,
+                    } => {
+                        let refcount = self.get_refcount(host_offset >> cluster_bits)?;
+                        (copied != (refcount == 1)).then_some(L2Entry::Normal {
+                            host_offset,
+                            copied: refcount == 1,
+                        })
+                    }
+                    L2Entry::ZeroPreallocated {
+                        host_offset,
+                        copied,
+                    } => {
+                        let refcount = self.get_refcount(host_offset >> cluster_bits)?;
+                        (copied != (refcount == 1)).then_some(L2Entry::ZeroPreallocated {
+                            host_offset,
+                            copied: refcount == 1,
+                        })
+                    }
+                    _ => None,
+                };
+
+                // The bitmap of extended entries is left as is
+                if let Some(entry) = fixed {
+                    self.write_l2_entry(entry_offset, entry, None)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CreateOptions, INCOMPAT_DIRTY, Qcow2};
+    use super::{CheckIssue, RepairMode};
+    use crate::testutil::TempFile;
+
+    // Creates an image with one data cluster and returns its host cluster
+    fn create(tmp: &TempFile) -> (Qcow2, u64) {
+        let opts = CreateOptions {
+            virtual_size: 1 << 20,
+            ..Default::default()
+        };
+        let mut q = Qcow2::create(tmp.path(), opts).unwrap();
+        q.write_at(0, &[1; 1 << 16]).unwrap();
+        let host_cluster = q.l2_entry(0).unwrap().host_offset().unwrap() >> q.cluster_bits();
+        (q, host_cluster)
+    }
+
+    #[test]
+    fn leaked_cluster_is_reported() {
+        let tmp = TempFile::new("check-leak");
+        let (mut q, _) = create(&tmp);
+        let leaked = q.alloc_clusters(1).unwrap() >> q.cluster_bits();
+
+        let report = q.check().unwrap();
+        assert_eq!(
+            report.issues,
+            [CheckIssue::Leak {
+                cluster: leaked,
+                refcount: 1
+            }]
+        );
+        assert_eq!((report.leaks, report.corruptions), (1, 0));
+    }
+
+    #[test]
+    fn wrong_refcount_is_reported() {
+        let tmp = TempFile::new("check-refcount");
+        let (mut q, data) = create(&tmp);
+
+        // The COPIED flag of the data cluster only matches a refcount of 1
+        for refcount in [3, 0] {
+            q.set_refcount(data, refcount).unwrap();
+            let report = q.check().unwrap();
+            assert_eq!(report.issues.len(), 2, "{:?}", report.issues);
+            assert!(report.issues.contains(&CheckIssue::RefcountMismatch {
+                cluster: data,
+                refcount,
+                references: 1
+            }));
+            assert!(
+                report
+                    .issues
+                    .iter()
+                    .any(|issue| matches!(issue, CheckIssue::CopiedFlag { .. }))
+            );
+            let leaks = if refcount > 1 { 1 } else { 0 };
+            assert_eq!((report.leaks, report.corruptions), (leaks, 2 - leaks));
+        }
+    }
+
+    #[test]
+    fn leaks_are_repaired_in_both_modes() {
+        for mode in [RepairMode::Leaks, RepairMode::All] {
+            let tmp = TempFile::new(&format!("repair-leak-{:?}", mode));
+            let (mut q, data) = create(&tmp);
+            q.alloc_clusters(1).unwrap();
+            q.set_refcount(data, 2).unwrap();
+
+            let report = q.repair(mode).unwrap();
+            assert!(report.is_clean(), "{:?}: {:?}", mode, report.issues);
+            assert_eq!(q.get_refcount(data).unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn missing_refcount_is_only_repaired_with_all() {
+        let tmp = TempFile::new("repair-missing");
+        let (mut q, data) = create(&tmp);
+        q.set_refcount(data, 0).unwrap();
+        q.header.incompatible_features |= INCOMPAT_DIRTY;
+        q.write_header().unwrap();
+        drop(q);
+
+        // Opened as is, a writable open would rebuild the refcounts
+        let mut q = Qcow2::open_for_repair(tmp.path()).unwrap();
+        let report = q.repair(RepairMode::Leaks).unwrap();
+        assert_eq!((report.leaks, report.corruptions), (0, 2));
+        assert_eq!(q.get_refcount(data).unwrap(), 0);
+        assert!(q.is_dirty());
+
+        let report = q.repair(RepairMode::All).unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+        assert_eq!(q.get_refcount(data).unwrap(), 1);
+        assert!(!q.is_dirty());
+        drop(q);
+
+        assert!(!Qcow2::new(tmp.path()).unwrap().is_dirty());
+    }
+}
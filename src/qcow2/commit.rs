@@ -0,0 +1,206 @@
+use log::debug;
+use std::fs;
+use std::io;
+
+use super::backing::{self, Backing};
+use super::{L1Entry, L2Entry, Qcow2};
+
+impl Qcow2 {
+    /// Writes every guest cluster allocated in the image (as data or zeros)
+    /// into its backing file, which can be raw or qcow2. When `empty` is set
+    /// the image is then emptied so it reads through to the backing file.
+    /// `progress` is called with the guest bytes processed and the virtual
+    /// size. Returns the number of clusters committed.
+    pub fn commit<F: FnMut(u64, u64)>(&mut self, empty: bool, mut progress: F) -> io::Result<u64> {
+        if empty {
+            self.check_writable()?;
+        }
+
+        let (backing_name, backing) = match (self.backing_file(), &self.backing) {
+            (Some(name), Some(backing)) => (name, backing),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Image has no backing file to commit into",
+                ));
+            }
+        };
+
+        let backing_path = backing::resolve_path(&self.path, &backing_name);
+        let mut target = backing.reopen_writable(&backing_path)?;
+
+        // Like qemu, a backing file smaller than the image is grown first
+        let virtual_size = self.virtual_size();
+        if target.size()? < virtual_size {
+            target.resize(virtual_size)?;
+        }
+
+        let cluster_sz = self.cluster_size() as u64;
+        let l2_entries = self.l2_entries();
+        let mut committed = 0;
+
+        for (l1_index, l1_entry) in self
+            .read_l1_table(self.l1_table_offset(), self.l1_size())?
+            .into_iter()
+            .enumerate()
+        {
+            let first = l1_index as u64 * l2_entries;
+            let l2_offset = match l1_entry {
+                L1Entry::Unallocated => continue,
+                L1Entry::L2Table { offset, .. } => offset,
+            };
+
+            for (l2_index, (entry, bitmap)) in
+                self.read_l2_table(l2_offset)?.into_iter().enumerate()
+            {
+                // Zero subclusters of an unallocated cluster hide the backing
+                // file too so they must be committed.
+                let allocated = match entry {
+                    L2Entry::Unallocated => bitmap.is_some_and(|b| b.0 != 0),
+                    _ => true,
+                };
+
+                let offset = (first + l2_index as u64) * cluster_sz;
+                if !allocated || offset >= virtual_size {
+                    continue;
+                }
+
+                let mut data = self.read_guest_cluster(first + l2_index as u64)?;
+                data.resize((virtual_size - offset).min(cluster_sz) as usize, 0);
+                target.write_all_at(&data, offset)?;
+                committed += 1;
+
+                progress(offset + data.len() as u64, virtual_size);
+            }
+        }
+
+        target.flush()?;
+        progress(virtual_size, virtual_size);
+
+        debug!("Committed {} cluster(s) into {:?}", committed, backing_path);
+
+        if empty {
+            self.make_empty()?;
+        }
+
+        // Reopen the backing file so its updated metadata is used for reads
+        let backing_fmt = self.backing_format().map(str::to_string);
+        let mut chain = vec![fs::canonicalize(&self.path)?];
+        self.backing = Some(Backing::open(
+            &self.path,
+            &backing_name,
+            backing_fmt.as_deref(),
+            &mut chain,
+        )?);
+
+        Ok(committed)
+    }
+
+    // Points the image to a new empty L1 table and drops the references of
+    // the previous one. A failure in between only leaks clusters.
+    fn make_empty(&mut self) -> io::Result<()> {
+        let old_l1_offset = self.header.l1_table_offset;
+        let l1_size = self.header.l1_size as u64;
+
+        let l1_offset = self.write_new_clusters(&vec![0u8; l1_size as usize * 8])?;
+        self.file.sync_data()?;
+
+        self.header.l1_table_offset = l1_offset;
+        self.write_header()?;
+
+        self.update_l1_refcounts(old_l1_offset, l1_size, false)?;
+        self.free_clusters(old_l1_offset, l1_size * 8)?;
+        self.update_copied_flags()?;
+        self.flush()?;
+
+        debug!("Emptied image, new L1 table at 0x{:016x}", l1_offset);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::super::{CreateOptions, L2Entry, Qcow2};
+    use crate::testutil::TempFile;
+
+    const CLUSTER_SIZE: usize = 1 << 16;
+
+    // Creates a backing file of 3 clusters of ones in the given format
+    fn create_base(tmp: &TempFile, fmt: &str) {
+        let data = vec![1u8; 3 * CLUSTER_SIZE];
+        if fmt == "raw" {
+            fs::write(tmp.path(), &data).unwrap();
+        } else {
+            let opts = CreateOptions {
+                virtual_size: data.len() as u64,
+                ..Default::default()
+            };
+            let mut q = Qcow2::create(tmp.path(), opts).unwrap();
+            q.write_at(0, &data).unwrap();
+        }
+    }
+
+    // Commits an overlay larger than its backing file and checks what both
+    // images read afterwards.
+    fn commit_into(fmt: &str, empty: bool) {
+        let name = format!("commit-{}-{}", fmt, empty);
+        let base = TempFile::new(&format!("{}-base", name));
+        create_base(&base, fmt);
+
+        let tmp = TempFile::new(&name);
+        let opts = CreateOptions {
+            virtual_size: 4 * CLUSTER_SIZE as u64,
+            backing_file: Some(base.path().to_string()),
+            backing_fmt: Some(fmt.to_string()),
+            ..Default::default()
+        };
+        let mut q = Qcow2::create(tmp.path(), opts).unwrap();
+        q.write_at(100, &[2; 1000]).unwrap();
+        q.write_zeroes(CLUSTER_SIZE as u64, CLUSTER_SIZE as u64, false)
+            .unwrap();
+        q.write_at(3 * CLUSTER_SIZE as u64, &[3; 10]).unwrap();
+
+        let mut expected = vec![1u8; 3 * CLUSTER_SIZE];
+        expected[100..1100].fill(2);
+        expected[CLUSTER_SIZE..2 * CLUSTER_SIZE].fill(0);
+        expected.resize(4 * CLUSTER_SIZE, 0);
+        expected[3 * CLUSTER_SIZE..3 * CLUSTER_SIZE + 10].fill(3);
+
+        assert_eq!(q.commit(empty, |_, _| {}).unwrap(), 3, "{}", name);
+        assert!(
+            q.read_at(0, 4 * CLUSTER_SIZE).unwrap() == expected,
+            "{}",
+            name
+        );
+        let unallocated = (0..4).all(|n| q.l2_entry(n).unwrap() == L2Entry::Unallocated);
+        assert_eq!(unallocated, empty, "{}", name);
+        let report = q.check().unwrap();
+        assert!(report.is_clean(), "{}: {:?}", name, report.issues);
+        drop(q);
+
+        let data = match fmt {
+            "raw" => fs::read(base.path()).unwrap(),
+            _ => {
+                let base = Qcow2::new(base.path()).unwrap();
+                assert_eq!(base.virtual_size(), 4 * CLUSTER_SIZE as u64);
+                assert!(base.check().unwrap().is_clean(), "{}", name);
+                base.read_at(0, 4 * CLUSTER_SIZE).unwrap()
+            }
+        };
+        assert!(data == expected, "{}", name);
+    }
+
+    #[test]
+    fn commit_into_raw_backing_file() {
+        commit_into("raw", false);
+        commit_into("raw", true);
+    }
+
+    #[test]
+    fn commit_into_qcow2_backing_file() {
+        commit_into("qcow2", false);
+        commit_into("qcow2", true);
+    }
+}
@@ -0,0 +1,479 @@
+use log::debug;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use super::entry::{L1E_OFFSET_MASK, L2E_OFFSET_MASK};
+use super::refcount::REFT_OFFSET_MASK;
+use super::{HeaderExtension, L1Entry, L2Entry, Qcow2, RepairMode};
+use crate::raw::read_padded;
+
+// Where the offset of a structure that is relocated is stored
+#[derive(Debug, Copy, Clone)]
+enum Reference {
+    L1Table,
+    RefcountTable,
+    SnapshotTable,
+    // An entry of a table, the bits of `mask` hold the offset
+    Entry { pos: u64, mask: u64 },
+    // Compressed data can start anywhere in the host clusters
+    Compressed { pos: u64 },
+}
+
+// Host clusters that are moved together, with everything that points to them
+struct Unit {
+    first: u64,
+    count: u64,
+    references: Vec<Reference>,
+}
+
+impl Unit {
+    fn new(first: u64, count: u64) -> Self {
+        Unit {
+            first,
+            count,
+            references: Vec::new(),
+        }
+    }
+}
+
+// Structures are moved by kind so the references to the ones being moved are
+// stored in structures that stay in place.
+#[derive(Debug, Copy, Clone)]
+enum Stage {
+    Data,
+    L2Tables,
+    L1Tables,
+    SnapshotTable,
+    RefcountBlocks,
+    RefcountTable,
+}
+
+const STAGES: [Stage; 6] = [
+    Stage::Data,
+    Stage::L2Tables,
+    Stage::L1Tables,
+    Stage::SnapshotTable,
+    Stage::RefcountBlocks,
+    Stage::RefcountTable,
+];
+
+impl Qcow2 {
+    /// Moves the clusters in use toward the beginning of the file, filling
+    /// the holes left by discarded data and deleted snapshots, and truncates
+    /// the file after the last one. Leaked clusters are reclaimed too. The
+    /// image must be otherwise consistent. Every cluster is copied before the
+    /// structures pointing to it are updated so an interruption only leaks
+    /// clusters. Returns the number of clusters moved.
+    pub fn compact(&mut self) -> io::Result<u64> {
+        self.check_writable()?;
+
+        if self.data_file.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Can't compact an image with an external data file",
+            ));
+        }
+        if self.extensions.iter().any(|ext| {
+            matches!(
+                ext,
+                HeaderExtension::Bitmaps { .. } | HeaderExtension::FullDiskEncryption { .. }
+            )
+        }) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Can't compact an image with bitmaps or an encryption header",
+            ));
+        }
+
+        let report = self.check()?;
+        if report.corruptions > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Image has {} corruption(s), it must be repaired first",
+                    report.corruptions
+                ),
+            ));
+        }
+        if report.leaks > 0 {
+            self.repair(RepairMode::Leaks)?;
+        }
+
+        let old_len = self.file.metadata()?.len();
+        self.free_cluster_index = 0;
+
+        // Moving a structure can free room for the ones of a previous stage
+        // so passes are repeated until nothing moves.
+        let mut moved = 0;
+        loop {
+            let mut pass_moved = 0;
+            for stage in STAGES {
+                // Units are sorted from the end of the file, when there is no
+                // room below one there is none for the next ones of the same
+                // size or larger.
+                let mut no_room = u64::MAX;
+                for unit in self.collect_units(stage)? {
+                    if unit.count >= no_room {
+                        continue;
+                    }
+                    if self.relocate(&unit)? {
+                        pass_moved += 1;
+                    } else {
+                        no_room = unit.count;
+                    }
+                }
+            }
+
+            if pass_moved == 0 {
+                break;
+            }
+            moved += pass_moved;
+        }
+
+        let end = self.check()?.image_end_offset;
+        if end < old_len {
+            self.file.set_len(end)?;
+        }
+        self.flush()?;
+
+        debug!(
+            "Compacted image: {} cluster(s) moved, {} bytes reclaimed",
+            moved,
+            old_len.saturating_sub(end)
+        );
+        Ok(moved)
+    }
+
+    // Returns the structures of a stage, the ones at the end of the file first
+    fn collect_units(&self, stage: Stage) -> io::Result<Vec<Unit>> {
+        let cluster_sz = self.cluster_size() as u64;
+        let cluster_bits = self.cluster_bits();
+
+        let mut units = match stage {
+            Stage::Data => self.data_units()?,
+            Stage::L2Tables => self.l2_table_units()?,
+            Stage::L1Tables => self
+                .l1_tables()?
+                .into_iter()
+                .filter(|&(_, l1_size, _)| l1_size > 0)
+                .map(|(l1_offset, l1_size, reference)| {
+                    let mut unit = Unit::new(
+                        l1_offset >> cluster_bits,
+                        (l1_size * 8).div_ceil(cluster_sz),
+                    );
+                    unit.references.push(reference);
+                    unit
+                })
+                .collect(),
+            Stage::SnapshotTable if self.nb_snapshots() > 0 => {
+                let len: u64 = self.snapshots()?.iter().map(|s| s.size()).sum();
+                let mut unit = Unit::new(
+                    self.snapshots_offset() >> cluster_bits,
+                    len.div_ceil(cluster_sz),
+                );
+                unit.references.push(Reference::SnapshotTable);
+                vec![unit]
+            }
+            Stage::SnapshotTable => Vec::new(),
+            Stage::RefcountBlocks => {
+                let table_offset = self.refcount_table_offset();
+                let mut table = vec![0u8; (self.refcount_table_clusters() * cluster_sz) as usize];
+                self.file.read_exact_at(&mut table, table_offset)?;
+
+                let mut units = Vec::new();
+                for (i, chunk) in table.chunks_exact(8).enumerate() {
+                    let block_offset =
+                        u64::from_be_bytes(chunk.try_into().unwrap()) & REFT_OFFSET_MASK;
+                    if block_offset == 0 {
+                        continue;
+                    }
+                    let mut unit = Unit::new(block_offset >> cluster_bits, 1);
+                    unit.references.push(Reference::Entry {
+                        pos: table_offset + i as u64 * 8,
+                        mask: REFT_OFFSET_MASK,
+                    });
+                    units.push(unit);
+                }
+                units
+            }
+            Stage::RefcountTable => {
+                let mut unit = Unit::new(
+                    self.refcount_table_offset() >> cluster_bits,
+                    self.refcount_table_clusters(),
+                );
+                unit.references.push(Reference::RefcountTable);
+                vec![unit]
+            }
+        };
+
+        units.sort_unstable_by_key(|unit| Reverse(unit.first));
+        Ok(units)
+    }
+
+    // Returns the offset and size of the active L1 table and of the ones of
+    // the snapshots, with the reference to each of them.
+    fn l1_tables(&self) -> io::Result<Vec<(u64, u64, Reference)>> {
+        let mut tables = vec![(
+            self.header.l1_table_offset,
+            self.header.l1_size as u64,
+            Reference::L1Table,
+        )];
+
+        let mut pos = self.snapshots_offset();
+        for snapshot in self.snapshots()? {
+            // The L1 table offset is the first field of the entry
+            tables.push((
+                snapshot.l1_table_offset,
+                snapshot.l1_size as u64,
+                Reference::Entry {
+                    pos,
+                    mask: u64::MAX,
+                },
+            ));
+            pos += snapshot.size();
+        }
+
+        Ok(tables)
+    }
+
+    // L2 tables can be shared between the active L1 table and the ones of
+    // the snapshots.
+    fn l2_table_units(&self) -> io::Result<Vec<Unit>> {
+        let cluster_bits = self.cluster_bits();
+        let mut units: HashMap<u64, Unit> = HashMap::new();
+
+        for (l1_offset, l1_size, _) in self.l1_tables()? {
+            for (i, entry) in self
+                .read_l1_table(l1_offset, l1_size)?
+                .into_iter()
+                .enumerate()
+            {
+                if let L1Entry::L2Table { offset, .. } = entry {
+                    let cluster = offset >> cluster_bits;
+                    units
+                        .entry(cluster)
+                        .or_insert_with(|| Unit::new(cluster, 1))
+                        .references
+                        .push(Reference::Entry {
+                            pos: l1_offset + i as u64 * 8,
+                            mask: L1E_OFFSET_MASK,
+                        });
+                }
+            }
+        }
+
+        Ok(units.into_values().collect())
+    }
+
+    // Data clusters can be shared too. Compressed data is not cluster aligned
+    // and can span two host clusters, which is shared with the neighbouring
+    // compressed data, so all of them are moved together.
+    fn data_units(&self) -> io::Result<Vec<Unit>> {
+        let cluster_bits = self.cluster_bits();
+        let entry_size = self.l2_entry_size();
+        let mut visited = HashSet::new();
+        let mut units: HashMap<u64, Unit> = HashMap::new();
+        let mut compressed = Vec::new();
+
+        for (l1_offset, l1_size, _) in self.l1_tables()? {
+            for entry in self.read_l1_table(l1_offset, l1_size)? {
+                let l2_offset = match entry {
+                    L1Entry::L2Table { offset, .. } if visited.insert(offset) => offset,
+                    _ => continue,
+                };
+
+                for (j, (entry, _)) in self.read_l2_table(l2_offset)?.into_iter().enumerate() {
+                    let pos = l2_offset + j as u64 * entry_size;
+                    match entry {
+                        L2Entry::Normal { host_offset, .. }
+                        | L2Entry::ZeroPreallocated { host_offset, .. } => {
+                            let cluster = host_offset >> cluster_bits;
+                            units
+                                .entry(cluster)
+                                .or_insert_with(|| Unit::new(cluster, 1))
+                                .references
+                                .push(Reference::Entry {
+                                    pos,
+                                    mask: L2E_OFFSET_MASK,
+                                });
+                        }
+                        L2Entry::Compressed { .. } => {
+                            let (first, last) = entry.host_clusters(cluster_bits).unwrap();
+                            compressed.push((first, last, Reference::Compressed { pos }));
+                        }
+                        L2Entry::Unallocated | L2Entry::Zero => {}
+                    }
+                }
+            }
+        }
+
+        let mut units: Vec<Unit> = units.into_values().collect();
+        compressed.sort_unstable_by_key(|&(first, _, _)| first);
+        let mut current: Option<Unit> = None;
+        for (first, last, reference) in compressed {
+            match current.as_mut() {
+                Some(unit) if first < unit.first + unit.count => {
+                    unit.count = unit.count.max(last + 1 - unit.first);
+                }
+                _ => {
+                    units.extend(current.take());
+                    current = Some(Unit::new(first, last + 1 - first));
+                }
+            }
+            current.as_mut().unwrap().references.push(reference);
+        }
+        units.extend(current);
+
+        Ok(units)
+    }
+
+    // Copies the unit to the first free clusters before it and points its
+    // references to the copy. Returns false if there is no room before it.
+    fn relocate(&mut self, unit: &Unit) -> io::Result<bool> {
+        let cluster_sz = self.cluster_size() as u64;
+        let hint = self.free_cluster_index;
+
+        let target = self.find_free_clusters(unit.count)?;
+        if target >= unit.first {
+            self.free_cluster_index = hint;
+            return Ok(false);
+        }
+
+        for i in 0..unit.count {
+            let refcount = self.get_refcount(unit.first + i)?;
+            self.set_refcount(target + i, refcount)?;
+        }
+
+        // Smaller runs may have been skipped while looking for this one
+        if unit.count > 1 {
+            self.free_cluster_index = hint;
+        }
+
+        // A refcount block can describe its own new location so it is read
+        // once the refcounts are set.
+        let old_offset = unit.first * cluster_sz;
+        let new_offset = target * cluster_sz;
+        let mut data = vec![0u8; (unit.count * cluster_sz) as usize];
+        read_padded(&self.file, &mut data, old_offset)?;
+        self.file.write_all_at(&data, new_offset)?;
+        self.file.sync_data()?;
+
+        for reference in &unit.references {
+            self.update_reference(*reference, old_offset, new_offset)?;
+        }
+        self.file.sync_data()?;
+
+        for i in 0..unit.count {
+            self.set_refcount(unit.first + i, 0)?;
+        }
+
+        debug!(
+            "Moved {} cluster(s) from 0x{:016x} to 0x{:016x}",
+            unit.count, old_offset, new_offset
+        );
+        Ok(true)
+    }
+
+    fn update_reference(
+        &mut self,
+        reference: Reference,
+        old_offset: u64,
+        new_offset: u64,
+    ) -> io::Result<()> {
+        match reference {
+            Reference::L1Table => {
+                self.header.l1_table_offset = new_offset;
+                self.write_header()
+            }
+            Reference::RefcountTable => {
+                self.header.refcount_table_offset = new_offset;
+                self.write_header()
+            }
+            Reference::SnapshotTable => {
+                self.header.snapshots_offset = new_offset;
+                self.write_header()
+            }
+            Reference::Entry { pos, mask } => {
+                let raw = self.read_entry(pos)?;
+                let offset = (raw & mask) - old_offset + new_offset;
+                self.write_entry(pos, (raw & !mask) | offset)
+            }
+            Reference::Compressed { pos } => match self.read_l2_entry(pos)? {
+                (L2Entry::Compressed { host_offset, size }, _) => {
+                    let entry = L2Entry::Compressed {
+                        host_offset: host_offset - old_offset + new_offset,
+                        size,
+                    };
+                    self.write_l2_entry(pos, entry, None)
+                }
+                (entry, _) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("L2 entry at 0x{:x} is not compressed: {:?}", pos, entry),
+                )),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CreateOptions, Qcow2};
+    use crate::testutil::TempFile;
+
+    const MIB: usize = 1 << 20;
+
+    fn assert_clean(q: &Qcow2) {
+        let report = q.check().unwrap();
+        assert_eq!(
+            (report.leaks, report.corruptions),
+            (0, 0),
+            "{:?}",
+            report.issues
+        );
+    }
+
+    #[test]
+    fn write_snapshot_discard_compact() {
+        let tmp = TempFile::new("compact-round-trip");
+        let opts = CreateOptions {
+            virtual_size: 16 * MIB as u64,
+            ..Default::default()
+        };
+        let mut q = Qcow2::create(tmp.path(), opts).unwrap();
+
+        // Every cluster has its own content
+        let mut expected: Vec<u8> = (0..8 * MIB).map(|i| (i >> 16) as u8 + 1).collect();
+        expected.resize(16 * MIB, 0);
+        q.write_at(0, &expected[..8 * MIB]).unwrap();
+        q.create_snapshot("before").unwrap();
+
+        // Copies on write the clusters shared with the snapshot
+        expected[2 * MIB..3 * MIB].fill(0xee);
+        q.write_at(2 * MIB as u64, &expected[2 * MIB..3 * MIB])
+            .unwrap();
+
+        expected[..4 * MIB].fill(0);
+        assert_eq!(q.discard(0, 4 * MIB as u64).unwrap(), 64);
+        q.create_snapshot("after").unwrap();
+        q.delete_snapshot("before").unwrap();
+        assert_clean(&q);
+
+        let len = q.file.metadata().unwrap().len();
+        assert!(q.compact().unwrap() > 0);
+        assert!(q.file.metadata().unwrap().len() < len);
+        assert_clean(&q);
+        assert!(q.read_at(0, 16 * MIB).unwrap() == expected);
+        drop(q);
+
+        // Nothing is left to move once compacted
+        let mut q = Qcow2::open(tmp.path(), true).unwrap();
+        assert_eq!(q.compact().unwrap(), 0);
+        assert_clean(&q);
+        drop(q);
+
+        let snapshot = Qcow2::open_snapshot(tmp.path(), "after").unwrap();
+        assert!(snapshot.read_at(0, 16 * MIB).unwrap() == expected);
+    }
+}
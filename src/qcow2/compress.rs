@@ -0,0 +1,154 @@
+use flate2::read::DeflateDecoder;
+use ruzstd::decoding::StreamingDecoder;
+use std::io::{self, Read};
+
+// Compression type stored in the header (only for version 3). When the field
+// is not present it is zlib.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CompressionType {
+    Zlib = 0,
+    Zstd = 1,
+}
+
+impl TryFrom<u64> for CompressionType {
+    type Error = io::Error;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CompressionType::Zlib),
+            1 => Ok(CompressionType::Zstd),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unknown compression type {}", value),
+            )),
+        }
+    }
+}
+
+// Decompresses `input` into `out` that must be a whole cluster. The input can
+// be longer than the compressed data as it is read by sectors.
+pub(super) fn decompress(
+    compression: CompressionType,
+    input: &[u8],
+    out: &mut [u8],
+) -> io::Result<()> {
+    match compression {
+        // Qemu uses raw deflate streams without zlib header
+        CompressionType::Zlib => DeflateDecoder::new(input).read_exact(out),
+        CompressionType::Zstd => {
+            // The cluster can be made of several frames so keep decoding until
+            // the cluster is full.
+            let mut input = input;
+            let mut done = 0;
+
+            while done < out.len() {
+                let mut decoder = StreamingDecoder::new(&mut input).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Failed to decode zstd frame: {}", e),
+                    )
+                })?;
+
+                let start = done;
+                loop {
+                    match decoder.read(&mut out[done..])? {
+                        0 => break,
+                        n => done += n,
+                    }
+                }
+
+                if done == start {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Compressed cluster is truncated",
+                    ));
+                }
+            }
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::Compression;
+    use flate2::write::DeflateEncoder;
+    use ruzstd::encoding::{CompressionLevel, compress_to_vec};
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+
+    use super::super::{CreateOptions, L1Entry, L2Entry, Qcow2};
+    use super::CompressionType;
+    use crate::testutil::TempFile;
+
+    const CLUSTER_SIZE: usize = 1 << 16;
+
+    fn cluster_data() -> Vec<u8> {
+        (0..CLUSTER_SIZE).map(|i| (i / 100 % 7) as u8).collect()
+    }
+
+    // Stores `compressed` as the data of guest cluster 1, guest cluster 0 is
+    // written to have an L2 table.
+    fn image_with_compressed_cluster(
+        tmp: &TempFile,
+        compression_type: CompressionType,
+        compressed: &[u8],
+    ) -> Qcow2 {
+        let opts = CreateOptions {
+            virtual_size: 1 << 20,
+            compression_type,
+            ..Default::default()
+        };
+        let mut q = Qcow2::create(tmp.path(), opts).unwrap();
+        q.write_at(0, &[1; CLUSTER_SIZE]).unwrap();
+
+        let host_offset = q.alloc_clusters(1).unwrap();
+        q.file.write_all_at(compressed, host_offset).unwrap();
+        let l2_offset = match q.l1_entry(0).unwrap() {
+            L1Entry::L2Table { offset, .. } => offset,
+            entry => panic!("Unexpected L1 entry {:?}", entry),
+        };
+        let entry = L2Entry::Compressed {
+            host_offset,
+            size: compressed.len() as u64,
+        };
+        q.write_l2_entry(l2_offset + 8, entry, None).unwrap();
+        q
+    }
+
+    fn assert_reads_cluster(q: &Qcow2, data: &[u8]) {
+        assert!(matches!(q.l2_entry(1).unwrap(), L2Entry::Compressed { .. }));
+        assert!(q.read_guest_cluster(1).unwrap() == data);
+        assert!(q.read_at(CLUSTER_SIZE as u64 - 10, 20).unwrap()[10..] == data[..10]);
+
+        let report = q.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+        assert_eq!(report.compressed_clusters, 1);
+    }
+
+    #[test]
+    fn deflate_cluster_is_decoded() {
+        let data = cluster_data();
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let tmp = TempFile::new("compress-deflate");
+        let q = image_with_compressed_cluster(&tmp, CompressionType::Zlib, &compressed);
+        assert_reads_cluster(&q, &data);
+    }
+
+    #[test]
+    fn zstd_cluster_is_decoded() {
+        // Qemu may write a cluster as several frames
+        let data = cluster_data();
+        let (first, second) = data.split_at(CLUSTER_SIZE / 4);
+        let mut compressed = compress_to_vec(first, CompressionLevel::Fastest);
+        compressed.extend(compress_to_vec(second, CompressionLevel::Fastest));
+
+        let tmp = TempFile::new("compress-zstd");
+        let q = image_with_compressed_cluster(&tmp, CompressionType::Zstd, &compressed);
+        assert_reads_cluster(&q, &data);
+    }
+}
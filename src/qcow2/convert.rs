@@ -0,0 +1,387 @@
+use log::debug;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use super::{CreateOptions, Qcow2};
+use crate::raw::{device_size, is_zero, read_padded};
+
+impl Qcow2 {
+    /// Converts the raw file or block device `src` into a new image `dst`.
+    /// The source is read by clusters and clusters that are all zeros are
+    /// left unallocated. The virtual size defaults to the size of the source
+    /// rounded up to a sector. `progress` is called with the number of bytes
+    /// processed and the total after each cluster.
+    pub fn convert_from_raw<F: FnMut(u64, u64)>(
+        src: &str,
+        dst: &str,
+        mut opts: CreateOptions,
+        mut progress: F,
+    ) -> io::Result<Qcow2> {
+        // Unallocated clusters would show the backing file instead of zeros
+        if opts.backing_file.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A converted image can't have a backing file",
+            ));
+        }
+
+        let raw = File::open(src)?;
+        let raw_len = device_size(&raw)?;
+        if opts.virtual_size == 0 {
+            opts.virtual_size = raw_len.next_multiple_of(512);
+        } else if opts.virtual_size < raw_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Virtual size {} is smaller than {} ({} bytes)",
+                    opts.virtual_size, src, raw_len
+                ),
+            ));
+        }
+
+        let mut q = Qcow2::create(dst, opts)?;
+        let cluster_sz = q.cluster_size() as u64;
+
+        let mut chunk = vec![0u8; cluster_sz as usize];
+        let mut allocated = 0;
+        let mut offset = 0;
+
+        while offset < raw_len {
+            let len = (raw_len - offset).min(cluster_sz) as usize;
+            raw.read_exact_at(&mut chunk[..len], offset)?;
+
+            if !is_zero(&chunk[..len]) {
+                q.write_at(offset, &chunk[..len])?;
+                allocated += 1;
+            }
+
+            offset += len as u64;
+            progress(offset, raw_len);
+        }
+
+        q.flush()?;
+
+        debug!(
+            "Converted {} to {}: {} of {} clusters allocated",
+            src,
+            dst,
+            allocated,
+            raw_len.div_ceil(cluster_sz)
+        );
+
+        Ok(q)
+    }
+
+    /// Writes the guest data of the image, including its backing chain, to
+    /// the raw file or block device `dst`. A regular file is truncated to the
+    /// virtual size and clusters that read as zeros are skipped so it stays
+    /// sparse, the ones mapped as zeros or unallocated down the backing chain
+    /// are not even read. `progress` is called like for `convert_from_raw`.
+    pub fn convert_to_raw<F: FnMut(u64, u64)>(&self, dst: &str, mut progress: F) -> io::Result<()> {
+        let virtual_size = self.virtual_size();
+        let cluster_sz = self.cluster_size() as u64;
+
+        let raw = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dst)?;
+
+        // A block device can't have holes so all clusters are written
+        let sparse = raw.metadata()?.is_file();
+        if sparse {
+            raw.set_len(0)?;
+            raw.set_len(virtual_size)?;
+        } else if device_size(&raw)? < virtual_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is smaller than the virtual size {}", dst, virtual_size),
+            ));
+        }
+
+        let zeros = vec![0u8; cluster_sz as usize];
+        let mut skipped = 0;
+        let mut offset = 0;
+
+        while offset < virtual_size {
+            let len = (virtual_size - offset).min(cluster_sz) as usize;
+
+            // Clusters mapped as zeros down the backing chain are not read
+            let n = offset / cluster_sz;
+            let data = match self.cluster_reads_as_zeros(n)? {
+                true => None,
+                false => {
+                    let mut data = self.read_guest_cluster(n)?;
+                    data.resize(len, 0);
+                    Some(data).filter(|data| !sparse || !is_zero(data))
+                }
+            };
+
+            match data {
+                Some(data) => raw.write_all_at(&data, offset)?,
+                None if !sparse => raw.write_all_at(&zeros[..len], offset)?,
+                None => skipped += 1,
+            }
+
+            offset += len as u64;
+            progress(offset, virtual_size);
+        }
+
+        raw.sync_all()?;
+
+        debug!(
+            "Converted image to {}: {} of {} clusters left as holes",
+            dst,
+            skipped,
+            virtual_size.div_ceil(cluster_sz)
+        );
+
+        Ok(())
+    }
+
+    /// Creates an overlay `dst` of the raw image `base` that holds only the
+    /// clusters where `modified` differs from it. The backing file name is
+    /// taken from the options or derived from the path of `base`, and its
+    /// format is always raw so the overlay can be used directly by qemu.
+    /// Past the end of `modified` the overlay reads as zeros.
+    pub fn create_delta<F: FnMut(u64, u64)>(
+        base: &str,
+        modified: &str,
+        dst: &str,
+        mut opts: CreateOptions,
+        mut progress: F,
+    ) -> io::Result<Qcow2> {
+        let base_file = File::open(base)?;
+        let modified_file = File::open(modified)?;
+        let modified_len = device_size(&modified_file)?;
+
+        if opts.backing_file.is_none() {
+            opts.backing_file = Some(backing_name(Path::new(base), Path::new(dst))?);
+        }
+        opts.backing_fmt = Some("raw".to_string());
+        if opts.virtual_size == 0 {
+            opts.virtual_size = modified_len.next_multiple_of(512);
+        }
+
+        let mut q = Qcow2::create(dst, opts)?;
+        let cluster_sz = q.cluster_size() as u64;
+
+        // The modified image reads as zeros past its end, up to the virtual
+        // size, so the data of a longer base must be hidden there. Beyond the
+        // end of both files everything reads as zeros.
+        let end = modified_len
+            .max(device_size(&base_file)?)
+            .min(q.virtual_size());
+
+        let mut base_chunk = vec![0u8; cluster_sz as usize];
+        let mut chunk = vec![0u8; cluster_sz as usize];
+        let mut allocated = 0;
+        let mut offset = 0;
+
+        while offset < end {
+            let len = (end - offset).min(cluster_sz) as usize;
+            read_padded(&modified_file, &mut chunk[..len], offset)?;
+            read_padded(&base_file, &mut base_chunk[..len], offset)?;
+
+            if chunk[..len] != base_chunk[..len] {
+                if is_zero(&chunk[..len]) {
+                    q.write_zeroes(offset, len as u64, false)?;
+                } else {
+                    q.write_at(offset, &chunk[..len])?;
+                }
+                allocated += 1;
+            }
+
+            offset += len as u64;
+            progress(offset, end);
+        }
+
+        q.flush()?;
+
+        debug!(
+            "Delta of {} over {} written to {}: {} of {} clusters allocated",
+            modified,
+            base,
+            dst,
+            allocated,
+            end.div_ceil(cluster_sz)
+        );
+
+        Ok(q)
+    }
+}
+
+// The backing file name is resolved from the directory of the overlay. A base
+// in the same directory is referenced by its file name so both files can be
+// moved together, otherwise its absolute path is used.
+fn backing_name(base: &Path, overlay: &Path) -> io::Result<String> {
+    let base = base.canonicalize()?;
+    let overlay_dir = match overlay.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.canonicalize()?,
+        _ => Path::new(".").canonicalize()?,
+    };
+
+    let name = match base.parent() {
+        Some(dir) if dir == overlay_dir => base.file_name().map(Path::new).unwrap_or(&base),
+        _ => &base,
+    };
+
+    name.to_str().map(str::to_string).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Backing file name {:?} is not valid UTF-8", name),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::super::{CreateOptions, L2Entry, Qcow2};
+    use crate::testutil::TempFile;
+
+    const CLUSTER_SIZE: usize = 1 << 16;
+
+    #[test]
+    fn convert_from_raw_allocates_non_zero_clusters() {
+        // Clusters 1 and 3 have data, the last one is partial
+        let mut data = vec![0u8; 4 * CLUSTER_SIZE + 1000];
+        data[CLUSTER_SIZE + 5] = 1;
+        data[4 * CLUSTER_SIZE - 1] = 2;
+        data[4 * CLUSTER_SIZE + 999] = 3;
+        let raw = TempFile::new("convert-from-raw.img");
+        fs::write(raw.path(), &data).unwrap();
+
+        let tmp = TempFile::new("convert-from-raw.qcow2");
+        let q =
+            Qcow2::convert_from_raw(raw.path(), tmp.path(), CreateOptions::default(), |_, _| {})
+                .unwrap();
+
+        assert_eq!(q.virtual_size(), data.len().next_multiple_of(512) as u64);
+        let allocated: Vec<u64> = (0..5)
+            .filter(|&n| q.l2_entry(n).unwrap() != L2Entry::Unallocated)
+            .collect();
+        assert_eq!(allocated, [1, 3, 4]);
+        data.resize(q.virtual_size() as usize, 0);
+        assert!(q.read_at(0, data.len()).unwrap() == data);
+
+        let report = q.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+        assert_eq!(report.allocated_clusters, 3);
+    }
+
+    fn create_overlay(path: &str, backing: &str, virtual_size: u64) -> Qcow2 {
+        let opts = CreateOptions {
+            virtual_size,
+            backing_file: Some(backing.to_string()),
+            backing_fmt: Some("qcow2".to_string()),
+            ..Default::default()
+        };
+        Qcow2::create(path, opts).unwrap()
+    }
+
+    #[test]
+    fn convert_to_raw_reads_through_backing_chain() {
+        let base = TempFile::new("convert-to-raw-base.qcow2");
+        let opts = CreateOptions {
+            virtual_size: 4 * CLUSTER_SIZE as u64,
+            ..Default::default()
+        };
+        let mut q = Qcow2::create(base.path(), opts).unwrap();
+        q.write_at(0, &[1; 4 * CLUSTER_SIZE]).unwrap();
+        drop(q);
+
+        let mid = TempFile::new("convert-to-raw-mid.qcow2");
+        let mut q = create_overlay(mid.path(), base.path(), 4 * CLUSTER_SIZE as u64);
+        q.write_zeroes(0, CLUSTER_SIZE as u64, false).unwrap();
+        q.write_at(3 * CLUSTER_SIZE as u64, &[4; 20]).unwrap();
+        drop(q);
+
+        // The top image is larger than its backing chain
+        let top = TempFile::new("convert-to-raw-top.qcow2");
+        let mut q = create_overlay(top.path(), mid.path(), 6 * CLUSTER_SIZE as u64);
+        q.write_at(CLUSTER_SIZE as u64 + 10, &[2; 100]).unwrap();
+        q.write_zeroes(2 * CLUSTER_SIZE as u64, CLUSTER_SIZE as u64, false)
+            .unwrap();
+        q.write_at(5 * CLUSTER_SIZE as u64, &[3; 10]).unwrap();
+
+        let mut expected = vec![1u8; 4 * CLUSTER_SIZE];
+        expected[..CLUSTER_SIZE].fill(0);
+        expected[CLUSTER_SIZE + 10..CLUSTER_SIZE + 110].fill(2);
+        expected[2 * CLUSTER_SIZE..3 * CLUSTER_SIZE].fill(0);
+        expected[3 * CLUSTER_SIZE..3 * CLUSTER_SIZE + 20].fill(4);
+        expected.resize(6 * CLUSTER_SIZE, 0);
+        expected[5 * CLUSTER_SIZE..5 * CLUSTER_SIZE + 10].fill(3);
+
+        let raw = TempFile::new("convert-to-raw.img");
+        q.convert_to_raw(raw.path(), |_, _| {}).unwrap();
+        assert!(fs::read(raw.path()).unwrap() == expected);
+    }
+
+    #[test]
+    fn create_delta_allocates_differing_clusters() {
+        let base_data: Vec<u8> = (0..4 * CLUSTER_SIZE).map(|i| (i % 249) as u8).collect();
+        let mut modified_data = base_data.clone();
+        modified_data[CLUSTER_SIZE + 7] ^= 0xff;
+        modified_data[3 * CLUSTER_SIZE..].fill(0);
+
+        let base = TempFile::new("delta-base.img");
+        let modified = TempFile::new("delta-modified.img");
+        fs::write(base.path(), &base_data).unwrap();
+        fs::write(modified.path(), &modified_data).unwrap();
+
+        let tmp = TempFile::new("delta.qcow2");
+        let q = Qcow2::create_delta(
+            base.path(),
+            modified.path(),
+            tmp.path(),
+            CreateOptions::default(),
+            |_, _| {},
+        )
+        .unwrap();
+
+        assert_eq!(q.l2_entry(0).unwrap(), L2Entry::Unallocated);
+        assert!(matches!(q.l2_entry(1).unwrap(), L2Entry::Normal { .. }));
+        assert_eq!(q.l2_entry(2).unwrap(), L2Entry::Unallocated);
+        assert_eq!(q.l2_entry(3).unwrap(), L2Entry::Zero);
+        assert!(q.read_at(0, 4 * CLUSTER_SIZE).unwrap() == modified_data);
+
+        let report = q.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+        assert_eq!(report.allocated_clusters, 1);
+    }
+
+    #[test]
+    fn create_delta_hides_longer_base() {
+        let base_data = vec![5u8; 2 * CLUSTER_SIZE];
+        let modified_data = vec![5u8; CLUSTER_SIZE + 1000];
+
+        let base = TempFile::new("delta-tail-base.img");
+        let modified = TempFile::new("delta-tail-modified.img");
+        fs::write(base.path(), &base_data).unwrap();
+        fs::write(modified.path(), &modified_data).unwrap();
+
+        let tmp = TempFile::new("delta-tail.qcow2");
+        let q = Qcow2::create_delta(
+            base.path(),
+            modified.path(),
+            tmp.path(),
+            CreateOptions::default(),
+            |_, _| {},
+        )
+        .unwrap();
+
+        // The virtual size is rounded up to a sector that reads as zeros
+        let size = (CLUSTER_SIZE + 1000).next_multiple_of(512);
+        let mut expected = modified_data.clone();
+        expected.resize(size, 0);
+        assert_eq!(q.virtual_size(), size as u64);
+        assert!(q.read_at(0, size).unwrap() == expected);
+
+        let report = q.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+    }
+}
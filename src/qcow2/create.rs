@@ -0,0 +1,332 @@
+use log::debug;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use super::backing::Backing;
+use super::extension::{FeatureName, FeatureType, HeaderExtension};
+use super::refcount::write_refcount;
+use super::{
+    CompressionType, EXTL2_MIN_CLUSTER_BITS, INCOMPAT_COMPRESSION_TYPE, INCOMPAT_EXTL2, Qcow2,
+    Qcow2Header,
+};
+
+// Qemu writes the compression type, the header is padded to 8 bytes
+const CREATE_V3_HEADER_LENGTH: u32 = 112;
+
+// Names of the features written in the feature name table, as qemu does
+const FEATURE_NAMES: &[(FeatureType, u8, &str)] = &[
+    (FeatureType::Incompatible, 0, "dirty bit"),
+    (FeatureType::Incompatible, 1, "corrupt bit"),
+    (FeatureType::Incompatible, 2, "external data file"),
+    (FeatureType::Incompatible, 3, "compression type"),
+    (FeatureType::Incompatible, 4, "extended L2 entries"),
+    (FeatureType::Compatible, 0, "lazy refcounts"),
+    (FeatureType::Autoclear, 0, "bitmaps"),
+    (FeatureType::Autoclear, 1, "raw external data"),
+];
+
+/// Options of a new image. A virtual size of 0 means the size of the backing
+/// file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreateOptions {
+    pub virtual_size: u64,
+    pub cluster_bits: u32,
+    pub version: u32,
+    pub refcount_order: u32,
+    pub backing_file: Option<String>,
+    pub backing_fmt: Option<String>,
+    pub extended_l2: bool,
+    pub compression_type: CompressionType,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        CreateOptions {
+            virtual_size: 0,
+            cluster_bits: 16,
+            version: 3,
+            refcount_order: 4,
+            backing_file: None,
+            backing_fmt: None,
+            extended_l2: false,
+            compression_type: CompressionType::Zlib,
+        }
+    }
+}
+
+fn invalid_option(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid create options: {}", reason),
+    )
+}
+
+impl CreateOptions {
+    fn validate(&self) -> io::Result<()> {
+        if !(9..=21).contains(&self.cluster_bits) {
+            return Err(invalid_option("cluster bits must be between 9 and 21"));
+        }
+
+        if !self.virtual_size.is_multiple_of(512) {
+            return Err(invalid_option("virtual size must be a multiple of 512"));
+        }
+
+        match self.version {
+            2 => {
+                if self.refcount_order != 4 {
+                    return Err(invalid_option("version 2 only has 16 bits refcounts"));
+                }
+                if self.extended_l2 || self.compression_type != CompressionType::Zlib {
+                    return Err(invalid_option("version 2 has no incompatible features"));
+                }
+            }
+            3 => {
+                if self.refcount_order > 6 {
+                    return Err(invalid_option("refcount order must be at most 6"));
+                }
+            }
+            _ => return Err(invalid_option("version must be 2 or 3")),
+        }
+
+        if self.extended_l2 && self.cluster_bits < EXTL2_MIN_CLUSTER_BITS {
+            return Err(invalid_option(
+                "extended L2 entries need clusters of 16 KiB",
+            ));
+        }
+
+        match (&self.backing_file, self.backing_fmt.as_deref()) {
+            (None, Some(_)) => Err(invalid_option("backing format without backing file")),
+            (_, None | Some("qcow2") | Some("raw")) => Ok(()),
+            (_, Some(fmt)) => Err(invalid_option(&format!(
+                "unsupported backing format {}",
+                fmt
+            ))),
+        }
+    }
+}
+
+// Returns the virtual size of the backing file that is used when no size is
+// given.
+fn backing_size(path: &Path, opts: &CreateOptions) -> io::Result<u64> {
+    let name = opts.backing_file.as_deref().unwrap_or_default();
+    match Backing::open(path, name, opts.backing_fmt.as_deref(), &mut Vec::new())? {
+        Backing::Raw(file) => file.metadata().map(|m| m.len()),
+        Backing::Qcow2(q) => Ok(q.virtual_size()),
+    }
+}
+
+impl Qcow2 {
+    /// Creates an empty image and opens it read-write. Like `qemu-img create`
+    /// the first cluster holds the header, it is followed by the refcount
+    /// table, the refcount blocks and the L1 table.
+    pub fn create(path: &str, opts: CreateOptions) -> io::Result<Qcow2> {
+        opts.validate()?;
+
+        let virtual_size = match (opts.virtual_size, &opts.backing_file) {
+            (0, Some(_)) => backing_size(Path::new(path), &opts)?,
+            (size, _) => size,
+        };
+
+        let cluster_sz = 1u64 << opts.cluster_bits;
+        let l2_entry_size = if opts.extended_l2 { 16 } else { 8 };
+        let l2_coverage = cluster_sz / l2_entry_size * cluster_sz;
+        let l1_size = virtual_size.div_ceil(l2_coverage);
+        if l1_size > u32::MAX as u64 {
+            return Err(invalid_option(
+                "virtual size is too large for the cluster size",
+            ));
+        }
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_sz);
+
+        // The refcount blocks must describe all the metadata clusters,
+        // including themselves and the refcount table.
+        let block_entries = cluster_sz * 8 / (1 << opts.refcount_order);
+        let mut table_clusters = 1;
+        let mut blocks = 1;
+        let total = loop {
+            let total = 1 + table_clusters + blocks + l1_clusters;
+            let needed_blocks = total.div_ceil(block_entries);
+            let needed_table = (needed_blocks * 8).div_ceil(cluster_sz);
+            if needed_blocks == blocks && needed_table == table_clusters {
+                break total;
+            }
+            blocks = needed_blocks;
+            table_clusters = needed_table;
+        };
+
+        let table_offset = cluster_sz;
+        let blocks_offset = table_offset + table_clusters * cluster_sz;
+        let l1_offset = blocks_offset + blocks * cluster_sz;
+
+        let mut incompatible_features = 0;
+        if opts.extended_l2 {
+            incompatible_features |= INCOMPAT_EXTL2;
+        }
+        if opts.compression_type != CompressionType::Zlib {
+            incompatible_features |= INCOMPAT_COMPRESSION_TYPE;
+        }
+
+        let mut header = Qcow2Header {
+            version: opts.version,
+            backing_file_offset: 0,
+            backing_file_size: 0,
+            cluster_bits: opts.cluster_bits,
+            size: virtual_size,
+            crypt_method: 0,
+            l1_size: l1_size as u32,
+            l1_table_offset: l1_offset,
+            refcount_table_offset: table_offset,
+            refcount_table_clusters: table_clusters as u32,
+            nb_snapshots: 0,
+            snapshots_offset: 0,
+            incompatible_features,
+            compatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: opts.refcount_order,
+            header_length: if opts.version == 2 {
+                72
+            } else {
+                CREATE_V3_HEADER_LENGTH
+            },
+            compression_type: opts.compression_type as u8,
+            tail: Vec::new(),
+        };
+        if opts.version == 3 {
+            header.tail = vec![0u8; (header.header_length - 105) as usize];
+        }
+
+        let mut extensions = Vec::new();
+        if let Some(fmt) = &opts.backing_fmt {
+            extensions.push(HeaderExtension::BackingFileFormat(fmt.clone()));
+        }
+        if opts.version == 3 {
+            let names = FEATURE_NAMES
+                .iter()
+                .map(|&(feature_type, bit, name)| FeatureName {
+                    feature_type,
+                    bit,
+                    name: name.to_string(),
+                })
+                .collect();
+            extensions.push(HeaderExtension::FeatureNameTable(names));
+        }
+
+        // The backing file name follows the extensions in the first cluster.
+        // The feature name table is only informative so it is left out when
+        // small clusters leave no room for it.
+        let backing_name = opts.backing_file.as_deref().unwrap_or_default().as_bytes();
+        let first_cluster_len = |extensions: &[HeaderExtension]| {
+            header.header_length as usize
+                + HeaderExtension::list_to_bytes(extensions).len()
+                + backing_name.len()
+        };
+        if first_cluster_len(&extensions) > cluster_sz as usize && opts.version == 3 {
+            debug!("No room for the feature name table in the first cluster");
+            extensions.pop();
+        }
+        if first_cluster_len(&extensions) > cluster_sz as usize {
+            return Err(invalid_option(
+                "backing file name doesn't fit in the first cluster",
+            ));
+        }
+
+        let extensions = HeaderExtension::list_to_bytes(&extensions);
+        let backing_offset = header.header_length as u64 + extensions.len() as u64;
+        if !backing_name.is_empty() {
+            header.backing_file_offset = backing_offset;
+            header.backing_file_size = backing_name.len() as u32;
+        }
+
+        let mut first_cluster = header.to_bytes();
+        first_cluster.extend_from_slice(&extensions);
+        first_cluster.extend_from_slice(backing_name);
+
+        let mut table = vec![0u8; (table_clusters * cluster_sz) as usize];
+        for i in 0..blocks {
+            let block_offset = blocks_offset + i * cluster_sz;
+            table[(i * 8) as usize..(i * 8 + 8) as usize]
+                .copy_from_slice(&block_offset.to_be_bytes());
+        }
+
+        let mut refcount_blocks = vec![0u8; (blocks * cluster_sz) as usize];
+        for cluster in 0..total {
+            let block = &mut refcount_blocks[((cluster / block_entries) * cluster_sz) as usize..]
+                [..cluster_sz as usize];
+            write_refcount(block, cluster % block_entries, opts.refcount_order, 1);
+        }
+
+        debug!(
+            "Creating {} with {} metadata clusters: refcount table at 0x{:x}, L1 table at 0x{:x}",
+            path, total, table_offset, l1_offset
+        );
+
+        let file: File = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        // The L1 table is all zeros so extending the file is enough
+        file.set_len(total * cluster_sz)?;
+        file.write_all_at(&table, table_offset)?;
+        file.write_all_at(&refcount_blocks, blocks_offset)?;
+        file.write_all_at(&first_cluster, 0)?;
+        file.sync_all()?;
+
+        Qcow2::open(path, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{L2Entry, Qcow2};
+    use super::CreateOptions;
+    use crate::testutil::TempFile;
+
+    #[test]
+    fn created_images_are_clean() {
+        // The large L1 table of 512 bytes clusters needs several refcount
+        // blocks with 64 bits refcounts.
+        for (cluster_bits, refcount_order, version, size) in [
+            (9, 0, 3, 64u64 << 20),
+            (9, 6, 3, 1 << 30),
+            (12, 1, 3, 64 << 20),
+            (14, 5, 3, 64 << 20),
+            (16, 6, 3, 64 << 20),
+            (21, 3, 3, 64 << 20),
+            (12, 4, 2, 64 << 20),
+        ] {
+            let name = format!("create-{}-{}-{}", cluster_bits, refcount_order, version);
+            let tmp = TempFile::new(&name);
+            let opts = CreateOptions {
+                virtual_size: size,
+                cluster_bits,
+                version,
+                refcount_order,
+                ..Default::default()
+            };
+            let mut q = Qcow2::create(tmp.path(), opts).unwrap();
+            assert_eq!(q.cluster_size(), 1 << cluster_bits, "{}", name);
+            assert_eq!(q.refcount_width(), 1 << refcount_order, "{}", name);
+            assert_eq!(q.version(), version as u64, "{}", name);
+
+            let report = q.check().unwrap();
+            assert!(report.is_clean(), "{}: {:?}", name, report.issues);
+
+            q.write_at(0, &[1; 5000]).unwrap();
+            q.write_at(size - 100, &[2; 100]).unwrap();
+            drop(q);
+
+            let q = Qcow2::new(tmp.path()).unwrap();
+            let report = q.check().unwrap();
+            assert!(report.is_clean(), "{}: {:?}", name, report.issues);
+            assert_eq!(q.read_at(0, 5000).unwrap(), vec![1; 5000], "{}", name);
+            assert_eq!(q.read_at(size - 100, 100).unwrap(), vec![2; 100]);
+            let last = (size >> cluster_bits) - 1;
+            assert!(matches!(q.l2_entry(last).unwrap(), L2Entry::Normal { .. }));
+        }
+    }
+}
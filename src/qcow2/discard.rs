@@ -0,0 +1,131 @@
+use log::debug;
+use std::io;
+
+use super::{L2Bitmap, L2Entry, Qcow2};
+use crate::raw::punch_hole;
+
+impl Qcow2 {
+    /// Deallocates the guest clusters entirely covered by the `len` bytes at
+    /// `offset`, the ones only partially covered are left as is. Without a
+    /// backing file the clusters become unallocated, otherwise they are
+    /// turned into zero clusters so the backing file doesn't show through
+    /// (version 2 images can't do it and read the backing file again). Host
+    /// clusters that are not used anymore are punched out of the file when
+    /// the file system allows it. Returns the number of clusters discarded.
+    pub fn discard(&mut self, offset: u64, len: u64) -> io::Result<u64> {
+        self.check_writable()?;
+        self.check_guest_range(offset, len)?;
+
+        let cluster_sz = self.cluster_size() as u64;
+        let first = offset.div_ceil(cluster_sz);
+        // The last cluster is entirely covered when the range reaches the
+        // end of the image.
+        let end = match offset + len {
+            end if end == self.virtual_size() => end.div_ceil(cluster_sz),
+            end => end / cluster_sz,
+        };
+
+        let (new_entry, new_bitmap) = match (self.backing_file.is_some(), self.is_extended_l2()) {
+            (false, false) => (L2Entry::Unallocated, None),
+            (false, true) => (L2Entry::Unallocated, Some(L2Bitmap(0))),
+            (true, true) => (L2Entry::Unallocated, Some(L2Bitmap::ALL_ZERO)),
+            (true, false) if self.version() >= 3 => (L2Entry::Zero, None),
+            (true, false) => (L2Entry::Unallocated, None),
+        };
+
+        let mut discarded = 0;
+        let mut freed = Vec::new();
+        for n in first..end {
+            let (entry, bitmap) = self.l2_entry_and_bitmap(n)?;
+            if entry == new_entry && bitmap == new_bitmap {
+                continue;
+            }
+
+            self.set_l2_entry(n, new_entry, new_bitmap)?;
+            self.release_host_clusters(&entry, &mut freed)?;
+            discarded += 1;
+        }
+
+        self.punch_clusters(freed);
+
+        debug!(
+            "Discarded {} cluster(s) in {} bytes at 0x{:016x}",
+            discarded, len, offset
+        );
+        Ok(discarded)
+    }
+
+    // Fails if the range goes beyond the virtual size
+    pub(super) fn check_guest_range(&self, offset: u64, len: u64) -> io::Result<()> {
+        let virtual_size = self.virtual_size();
+        match offset.checked_add(len) {
+            Some(end) if end <= virtual_size => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Range of {} bytes at 0x{:016x} is beyond virtual size {}",
+                    len, offset, virtual_size
+                ),
+            )),
+        }
+    }
+
+    // Replaces the L2 entry of guest cluster N, the L2 table is allocated or
+    // copied if needed.
+    pub(super) fn set_l2_entry(
+        &mut self,
+        n: u64,
+        entry: L2Entry,
+        bitmap: Option<L2Bitmap>,
+    ) -> io::Result<()> {
+        let l2_offset = self.get_writable_l2_table(n)?;
+        let entry_offset = l2_offset + (n % self.l2_entries()) * self.l2_entry_size();
+        self.write_l2_entry(entry_offset, entry, bitmap)
+    }
+
+    // Drops the references of a previous L2 entry to its host clusters. The
+    // ones that are not used anymore are added to `freed`.
+    pub(super) fn release_host_clusters(
+        &mut self,
+        entry: &L2Entry,
+        freed: &mut Vec<u64>,
+    ) -> io::Result<()> {
+        if let Some((first, last)) = entry.host_clusters(self.cluster_bits()) {
+            for cluster in first..=last {
+                self.decrement_refcount(cluster)?;
+                if self.get_refcount(cluster)? == 0 {
+                    freed.push(cluster);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Punches holes where the host clusters were, contiguous clusters are
+    // punched at once. It is only an optimization so failures are ignored.
+    pub(super) fn punch_clusters(&self, mut clusters: Vec<u64>) {
+        // A freed cluster may have been reused meanwhile for an L2 table
+        clusters.retain(|&cluster| self.get_refcount(cluster).is_ok_and(|r| r == 0));
+        clusters.sort_unstable();
+        clusters.dedup();
+
+        let cluster_bits = self.cluster_bits();
+        let mut i = 0;
+        while i < clusters.len() {
+            let first = clusters[i];
+            let mut count = 1;
+            while clusters.get(i + count as usize) == Some(&(first + count)) {
+                count += 1;
+            }
+            i += count as usize;
+
+            let (offset, len) = (first << cluster_bits, count << cluster_bits);
+            if let Err(e) = punch_hole(self.data_file(), offset, len) {
+                debug!(
+                    "Can't punch a hole of {} bytes at 0x{:016x}: {}",
+                    len, offset, e
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use std::io;
+
+// Flags of L1 and L2 entries
+pub(super) const QCOW_OFLAG_COPIED: u64 = 1 << 63;
+const QCOW_OFLAG_COMPRESSED: u64 = 1 << 62;
+const QCOW_OFLAG_ZERO: u64 = 1;
+
+// Bits 9-55 of L1 entries and standard L2 entries are the host offset
+pub(super) const L1E_OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+pub(super) const L2E_OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+
+// Bits that must be zero. For standard L2 entries the bit 0 is only valid
+// since version 3.
+const L1E_RESERVED_MASK: u64 = 0x7F00_0000_0000_01FF;
+const L2E_STD_RESERVED_MASK: u64 = 0x3F00_0000_0000_01FE;
+
+fn corrupted(kind: &str, raw: u64, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Corrupted {} entry 0x{:016x}: {}", kind, raw, reason),
+    )
+}
+
+/// An entry of the L1 table that points to an L2 table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum L1Entry {
+    Unallocated,
+    L2Table { offset: u64, copied: bool },
+}
+
+impl L1Entry {
+    pub fn parse(raw: u64, cluster_size: u64) -> io::Result<Self> {
+        if raw & L1E_RESERVED_MASK != 0 {
+            return Err(corrupted("L1", raw, "reserved bits are set"));
+        }
+
+        let offset = raw & L1E_OFFSET_MASK;
+        if offset == 0 {
+            return Ok(L1Entry::Unallocated);
+        }
+
+        if !offset.is_multiple_of(cluster_size) {
+            return Err(corrupted("L1", raw, "L2 table is not cluster aligned"));
+        }
+
+        Ok(L1Entry::L2Table {
+            offset,
+            copied: raw & QCOW_OFLAG_COPIED != 0,
+        })
+    }
+
+    pub fn to_raw(self) -> u64 {
+        match self {
+            L1Entry::Unallocated => 0,
+            L1Entry::L2Table { offset, copied } => {
+                offset | if copied { QCOW_OFLAG_COPIED } else { 0 }
+            }
+        }
+    }
+}
+
+/// An entry of an L2 table that describes where the data of a guest cluster
+/// lives. With extended L2 entries it is followed by an [`L2Bitmap`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum L2Entry {
+    /// Data is read from the backing file, or zeros if there is none.
+    Unallocated,
+    /// Data reads as zeros and there is no host cluster.
+    Zero,
+    /// Data reads as zeros but a host cluster is kept for future writes.
+    ZeroPreallocated {
+        host_offset: u64,
+        copied: bool,
+    },
+    Normal {
+        host_offset: u64,
+        copied: bool,
+    },
+    /// `size` is the maximum size in bytes of the compressed data.
+    Compressed {
+        host_offset: u64,
+        size: u64,
+    },
+}
+
+impl L2Entry {
+    pub fn parse(raw: u64, cluster_bits: u32, version: u64) -> io::Result<Self> {
+        if raw & QCOW_OFLAG_COMPRESSED != 0 {
+            if raw & QCOW_OFLAG_COPIED != 0 {
+                return Err(corrupted("L2", raw, "compressed cluster is COPIED"));
+            }
+
+            // Bits 0 to x-1 are the host offset and bits x to 61 are the
+            // number of additional 512 bytes sectors.
+            let x = 62 - (cluster_bits as u64 - 8);
+            let host_offset = raw & ((1 << x) - 1);
+            let nb_sectors = ((raw >> x) & ((1 << (cluster_bits - 8)) - 1)) + 1;
+
+            return Ok(L2Entry::Compressed {
+                host_offset,
+                size: nb_sectors * 512 - (host_offset & 511),
+            });
+        }
+
+        if raw & L2E_STD_RESERVED_MASK != 0 {
+            return Err(corrupted("L2", raw, "reserved bits are set"));
+        }
+
+        let zero = raw & QCOW_OFLAG_ZERO != 0;
+        if zero && version < 3 {
+            return Err(corrupted("L2", raw, "zero flag is only valid in version 3"));
+        }
+
+        let host_offset = raw & L2E_OFFSET_MASK;
+        let copied = raw & QCOW_OFLAG_COPIED != 0;
+
+        if !host_offset.is_multiple_of(1 << cluster_bits) {
+            return Err(corrupted("L2", raw, "data cluster is not cluster aligned"));
+        }
+
+        Ok(match (host_offset, zero) {
+            (0, false) => L2Entry::Unallocated,
+            (0, true) => L2Entry::Zero,
+            (_, true) => L2Entry::ZeroPreallocated {
+                host_offset,
+                copied,
+            },
+            (_, false) => L2Entry::Normal {
+                host_offset,
+                copied,
+            },
+        })
+    }
+
+    /// Parses an extended L2 entry. The zero flag is replaced by the bitmap
+    /// that gives the status of each subcluster.
+    pub fn parse_extended(
+        raw: u64,
+        bitmap: u64,
+        cluster_bits: u32,
+    ) -> io::Result<(Self, L2Bitmap)> {
+        if raw & QCOW_OFLAG_COMPRESSED == 0 && raw & QCOW_OFLAG_ZERO != 0 {
+            return Err(corrupted(
+                "L2",
+                raw,
+                "zero flag is reserved with extended L2",
+            ));
+        }
+
+        let entry = L2Entry::parse(raw, cluster_bits, 3)?;
+        let bitmap = L2Bitmap(bitmap);
+
+        let reason = match entry {
+            L2Entry::Compressed { .. } if bitmap.0 != 0 => "compressed cluster has a bitmap",
+            L2Entry::Unallocated if bitmap.allocated() != 0 => {
+                "unallocated cluster has allocated subclusters"
+            }
+            _ if bitmap.allocated() & bitmap.zero() != 0 => "subcluster is allocated and zero",
+            _ => return Ok((entry, bitmap)),
+        };
+
+        Err(corrupted("extended L2", raw, reason))
+    }
+
+    pub fn to_raw(self, cluster_bits: u32) -> u64 {
+        let copied_flag = |copied| if copied { QCOW_OFLAG_COPIED } else { 0 };
+
+        match self {
+            L2Entry::Unallocated => 0,
+            L2Entry::Zero => QCOW_OFLAG_ZERO,
+            L2Entry::ZeroPreallocated {
+                host_offset,
+                copied,
+            } => host_offset | QCOW_OFLAG_ZERO | copied_flag(copied),
+            L2Entry::Normal {
+                host_offset,
+                copied,
+            } => host_offset | copied_flag(copied),
+            L2Entry::Compressed { host_offset, size } => {
+                let x = 62 - (cluster_bits as u64 - 8);
+                let nb_sectors = ((host_offset & 511) + size).div_ceil(512) - 1;
+                QCOW_OFLAG_COMPRESSED | (nb_sectors << x) | host_offset
+            }
+        }
+    }
+
+    /// Returns the host offset of the cluster if there is one.
+    pub fn host_offset(&self) -> Option<u64> {
+        match *self {
+            L2Entry::Unallocated | L2Entry::Zero => None,
+            L2Entry::ZeroPreallocated { host_offset, .. }
+            | L2Entry::Normal { host_offset, .. }
+            | L2Entry::Compressed { host_offset, .. } => Some(host_offset),
+        }
+    }
+
+    /// Returns the range of host clusters referenced by this entry. Compressed
+    /// data can span two host clusters.
+    pub fn host_clusters(&self, cluster_bits: u32) -> Option<(u64, u64)> {
+        match *self {
+            L2Entry::Unallocated | L2Entry::Zero => None,
+            L2Entry::ZeroPreallocated { host_offset, .. } | L2Entry::Normal { host_offset, .. } => {
+                Some((host_offset >> cluster_bits, host_offset >> cluster_bits))
+            }
+            L2Entry::Compressed { host_offset, size } => Some((
+                host_offset >> cluster_bits,
+                (host_offset + size - 1) >> cluster_bits,
+            )),
+        }
+    }
+}
+
+/// Status of a subcluster in an image with extended L2 entries.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Subcluster {
+    /// Data is read from the backing file, or zeros if there is none.
+    Unallocated,
+    Zero,
+    /// Data is read from the host cluster.
+    Allocated,
+}
+
+/// Second half of an extended L2 entry. Bits 0-31 tell that a subcluster is
+/// allocated and bits 32-63 that it reads as zeros.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct L2Bitmap(pub u64);
+
+impl L2Bitmap {
+    pub const SUBCLUSTERS: u64 = 32;
+    pub const ALL_ALLOCATED: L2Bitmap = L2Bitmap(0x0000_0000_FFFF_FFFF);
+    pub const ALL_ZERO: L2Bitmap = L2Bitmap(0xFFFF_FFFF_0000_0000);
+
+    pub(super) fn allocated(self) -> u32 {
+        self.0 as u32
+    }
+
+    pub(super) fn zero(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    pub fn subcluster(self, index: u64) -> Subcluster {
+        if self.zero() & (1 << index) != 0 {
+            Subcluster::Zero
+        } else if self.allocated() & (1 << index) != 0 {
+            Subcluster::Allocated
+        } else {
+            Subcluster::Unallocated
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLUSTER_BITS: u32 = 16;
+    const HOST: u64 = 0x5_0000;
+
+    #[test]
+    fn l1_entry_reserved_bits_and_alignment() {
+        assert_eq!(
+            L1Entry::parse(0, 1 << CLUSTER_BITS).unwrap(),
+            L1Entry::Unallocated
+        );
+        assert_eq!(
+            L1Entry::parse(HOST | QCOW_OFLAG_COPIED, 1 << CLUSTER_BITS).unwrap(),
+            L1Entry::L2Table {
+                offset: HOST,
+                copied: true
+            }
+        );
+
+        for raw in [HOST | 1, HOST | 1 << 56, HOST | QCOW_OFLAG_COMPRESSED] {
+            assert!(
+                L1Entry::parse(raw, 1 << CLUSTER_BITS).is_err(),
+                "0x{:x}",
+                raw
+            );
+        }
+        assert!(L1Entry::parse(HOST + 512, 1 << CLUSTER_BITS).is_err());
+    }
+
+    #[test]
+    fn l2_entry_kinds() {
+        let parse = |raw| L2Entry::parse(raw, CLUSTER_BITS, 3).unwrap();
+
+        assert_eq!(parse(0), L2Entry::Unallocated);
+        assert_eq!(parse(QCOW_OFLAG_ZERO), L2Entry::Zero);
+        assert_eq!(
+            parse(HOST | QCOW_OFLAG_ZERO | QCOW_OFLAG_COPIED),
+            L2Entry::ZeroPreallocated {
+                host_offset: HOST,
+                copied: true
+            }
+        );
+        assert_eq!(
+            parse(HOST),
+            L2Entry::Normal {
+                host_offset: HOST,
+                copied: false
+            }
+        );
+    }
+
+    #[test]
+    fn l2_entry_reserved_bits_and_alignment() {
+        for raw in [HOST | 2, HOST | 1 << 56, HOST | 1 << 61] {
+            assert!(L2Entry::parse(raw, CLUSTER_BITS, 3).is_err(), "0x{:x}", raw);
+        }
+        assert!(L2Entry::parse(HOST + 512, CLUSTER_BITS, 3).is_err());
+        // The zero flag only exists since version 3
+        assert!(L2Entry::parse(QCOW_OFLAG_ZERO, CLUSTER_BITS, 2).is_err());
+        assert!(
+            L2Entry::parse(
+                QCOW_OFLAG_COMPRESSED | QCOW_OFLAG_COPIED | HOST,
+                CLUSTER_BITS,
+                3
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn l2_entry_round_trips() {
+        let entries = [
+            L2Entry::Unallocated,
+            L2Entry::Zero,
+            L2Entry::ZeroPreallocated {
+                host_offset: HOST,
+                copied: false,
+            },
+            L2Entry::Normal {
+                host_offset: HOST,
+                copied: true,
+            },
+            // Compressed data can start anywhere and span two clusters, its
+            // size ends on a sector.
+            L2Entry::Compressed {
+                host_offset: HOST + 0x1234,
+                size: 0x10200 - 0x34,
+            },
+        ];
+
+        for entry in entries {
+            let raw = entry.to_raw(CLUSTER_BITS);
+            assert_eq!(L2Entry::parse(raw, CLUSTER_BITS, 3).unwrap(), entry);
+        }
+
+        let compressed = entries[4];
+        assert_eq!(
+            compressed.host_clusters(CLUSTER_BITS),
+            Some((HOST >> CLUSTER_BITS, (HOST >> CLUSTER_BITS) + 1))
+        );
+    }
+
+    #[test]
+    fn extended_l2_entry_validation() {
+        let parse = |raw, bitmap| L2Entry::parse_extended(raw, bitmap, CLUSTER_BITS);
+
+        let (entry, bitmap) = parse(HOST, 0x0000_00f0_0000_000f).unwrap();
+        assert!(matches!(entry, L2Entry::Normal { .. }));
+        assert_eq!(bitmap.subcluster(0), Subcluster::Allocated);
+        assert_eq!(bitmap.subcluster(4), Subcluster::Zero);
+        assert_eq!(bitmap.subcluster(8), Subcluster::Unallocated);
+
+        // The zero flag is replaced by the bitmap
+        assert!(parse(QCOW_OFLAG_ZERO, 0).is_err());
+        assert!(parse(QCOW_OFLAG_COMPRESSED | HOST, 1).is_err());
+        assert!(parse(0, 1).is_err());
+        assert!(parse(HOST, 1 | 1 << 32).is_err());
+    }
+}
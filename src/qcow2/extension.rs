@@ -0,0 +1,381 @@
+use serde::Serialize;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use super::Qcow2Header;
+use super::header::{be_u32, be_u64};
+
+// Types of the header extensions
+const EXT_END: u32 = 0x0000_0000;
+const EXT_BACKING_FILE_FORMAT: u32 = 0xe279_2aca;
+const EXT_FEATURE_NAME_TABLE: u32 = 0x6803_f857;
+const EXT_BITMAPS: u32 = 0x2385_2875;
+const EXT_FULL_DISK_ENCRYPTION: u32 = 0x0537_be77;
+const EXT_EXTERNAL_DATA_FILE: u32 = 0x4441_5441;
+
+// An entry of the feature name table is 48 bytes: type, bit number and a
+// name padded with zeros.
+const FEATURE_NAME_ENTRY_SIZE: usize = 48;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum FeatureType {
+    Incompatible,
+    Compatible,
+    Autoclear,
+    Unknown(u8),
+}
+
+impl From<u8> for FeatureType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => FeatureType::Incompatible,
+            1 => FeatureType::Compatible,
+            2 => FeatureType::Autoclear,
+            v => FeatureType::Unknown(v),
+        }
+    }
+}
+
+impl From<FeatureType> for u8 {
+    fn from(value: FeatureType) -> Self {
+        match value {
+            FeatureType::Incompatible => 0,
+            FeatureType::Compatible => 1,
+            FeatureType::Autoclear => 2,
+            FeatureType::Unknown(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FeatureName {
+    pub feature_type: FeatureType,
+    pub bit: u8,
+    pub name: String,
+}
+
+/// Optional header extension stored after the header in the first cluster.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum HeaderExtension {
+    BackingFileFormat(String),
+    FeatureNameTable(Vec<FeatureName>),
+    Bitmaps {
+        nb_bitmaps: u32,
+        /// Must be zero, it is kept as is to be written back unchanged
+        reserved: u32,
+        bitmap_directory_size: u64,
+        bitmap_directory_offset: u64,
+    },
+    FullDiskEncryption {
+        offset: u64,
+        length: u64,
+    },
+    ExternalDataFile(String),
+    // Extensions we don't know are kept so they can be written back
+    Unknown {
+        ext_type: u32,
+        data: Vec<u8>,
+    },
+}
+
+fn invalid(ext_type: u32, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid header extension 0x{:08x}: {}", ext_type, reason),
+    )
+}
+
+fn to_string(ext_type: u32, data: &[u8]) -> io::Result<String> {
+    String::from_utf8(data.to_vec()).map_err(|_| invalid(ext_type, "name is not valid UTF-8"))
+}
+
+impl HeaderExtension {
+    fn parse(ext_type: u32, data: &[u8]) -> io::Result<Self> {
+        let ext = match ext_type {
+            EXT_BACKING_FILE_FORMAT => {
+                HeaderExtension::BackingFileFormat(to_string(ext_type, data)?)
+            }
+            EXT_FEATURE_NAME_TABLE => {
+                let names = data
+                    .chunks_exact(FEATURE_NAME_ENTRY_SIZE)
+                    .map(|entry| {
+                        let name = &entry[2..];
+                        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                        FeatureName {
+                            feature_type: FeatureType::from(entry[0]),
+                            bit: entry[1],
+                            name: String::from_utf8_lossy(&name[..len]).into_owned(),
+                        }
+                    })
+                    .collect();
+                HeaderExtension::FeatureNameTable(names)
+            }
+            EXT_BITMAPS => {
+                if data.len() < 24 {
+                    return Err(invalid(ext_type, "data is too short"));
+                }
+                HeaderExtension::Bitmaps {
+                    nb_bitmaps: be_u32(data, 0),
+                    reserved: be_u32(data, 4),
+                    bitmap_directory_size: be_u64(data, 8),
+                    bitmap_directory_offset: be_u64(data, 16),
+                }
+            }
+            EXT_FULL_DISK_ENCRYPTION => {
+                if data.len() < 16 {
+                    return Err(invalid(ext_type, "data is too short"));
+                }
+                HeaderExtension::FullDiskEncryption {
+                    offset: be_u64(data, 0),
+                    length: be_u64(data, 8),
+                }
+            }
+            EXT_EXTERNAL_DATA_FILE => HeaderExtension::ExternalDataFile(to_string(ext_type, data)?),
+            _ => HeaderExtension::Unknown {
+                ext_type,
+                data: data.to_vec(),
+            },
+        };
+
+        Ok(ext)
+    }
+
+    /// Reads the list of extensions that follows the header. It stops at the
+    /// end marker or at the end of the first cluster.
+    pub fn read_all(file: &File, header: &Qcow2Header) -> io::Result<Vec<Self>> {
+        let cluster_sz = 1usize << header.cluster_bits;
+        let start = header.header_length as usize;
+        if start >= cluster_sz {
+            return Ok(Vec::new());
+        }
+
+        // The file can be shorter than a cluster, only the data that is read
+        // is parsed.
+        let mut buf = vec![0u8; cluster_sz - start];
+        let mut len = 0;
+        while len < buf.len() {
+            match file.read_at(&mut buf[len..], (start + len) as u64)? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        buf.truncate(len);
+
+        let mut extensions = Vec::new();
+        let mut offset = 0;
+
+        while offset + 8 <= buf.len() {
+            let ext_type = be_u32(&buf, offset);
+            let ext_len = be_u32(&buf, offset + 4) as usize;
+
+            if ext_type == EXT_END {
+                break;
+            }
+
+            let data_start = offset + 8;
+            if data_start + ext_len > buf.len() {
+                return Err(invalid(ext_type, "data goes beyond the first cluster"));
+            }
+
+            extensions.push(HeaderExtension::parse(
+                ext_type,
+                &buf[data_start..data_start + ext_len],
+            )?);
+
+            // Data is padded to a multiple of 8 bytes
+            offset = data_start + ext_len.div_ceil(8) * 8;
+        }
+
+        Ok(extensions)
+    }
+
+    pub fn ext_type(&self) -> u32 {
+        match self {
+            HeaderExtension::BackingFileFormat(_) => EXT_BACKING_FILE_FORMAT,
+            HeaderExtension::FeatureNameTable(_) => EXT_FEATURE_NAME_TABLE,
+            HeaderExtension::Bitmaps { .. } => EXT_BITMAPS,
+            HeaderExtension::FullDiskEncryption { .. } => EXT_FULL_DISK_ENCRYPTION,
+            HeaderExtension::ExternalDataFile(_) => EXT_EXTERNAL_DATA_FILE,
+            HeaderExtension::Unknown { ext_type, .. } => *ext_type,
+        }
+    }
+
+    fn data(&self) -> Vec<u8> {
+        match self {
+            HeaderExtension::BackingFileFormat(name) | HeaderExtension::ExternalDataFile(name) => {
+                name.as_bytes().to_vec()
+            }
+            HeaderExtension::FeatureNameTable(names) => {
+                let mut data = Vec::with_capacity(names.len() * FEATURE_NAME_ENTRY_SIZE);
+                for feature in names {
+                    let mut entry = [0u8; FEATURE_NAME_ENTRY_SIZE];
+                    entry[0] = feature.feature_type.into();
+                    entry[1] = feature.bit;
+                    let name = feature.name.as_bytes();
+                    let len = name.len().min(FEATURE_NAME_ENTRY_SIZE - 2);
+                    entry[2..2 + len].copy_from_slice(&name[..len]);
+                    data.extend_from_slice(&entry);
+                }
+                data
+            }
+            HeaderExtension::Bitmaps {
+                nb_bitmaps,
+                reserved,
+                bitmap_directory_size,
+                bitmap_directory_offset,
+            } => {
+                let mut data = Vec::with_capacity(24);
+                data.extend_from_slice(&nb_bitmaps.to_be_bytes());
+                data.extend_from_slice(&reserved.to_be_bytes());
+                data.extend_from_slice(&bitmap_directory_size.to_be_bytes());
+                data.extend_from_slice(&bitmap_directory_offset.to_be_bytes());
+                data
+            }
+            HeaderExtension::FullDiskEncryption { offset, length } => {
+                let mut data = Vec::with_capacity(16);
+                data.extend_from_slice(&offset.to_be_bytes());
+                data.extend_from_slice(&length.to_be_bytes());
+                data
+            }
+            HeaderExtension::Unknown { data, .. } => data.clone(),
+        }
+    }
+
+    /// Returns the extension as it is stored on disk, padded to 8 bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let data = self.data();
+        let mut buf = Vec::with_capacity(8 + data.len().div_ceil(8) * 8);
+
+        buf.extend_from_slice(&self.ext_type().to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&data);
+        buf.resize(8 + data.len().div_ceil(8) * 8, 0);
+
+        buf
+    }
+
+    /// Returns the extensions followed by the end marker.
+    pub fn list_to_bytes(extensions: &[HeaderExtension]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for ext in extensions {
+            buf.extend_from_slice(&ext.to_bytes());
+        }
+        buf.extend_from_slice(&[0u8; 8]);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempFile;
+
+    fn header(cluster_bits: u32) -> Qcow2Header {
+        Qcow2Header {
+            version: 3,
+            backing_file_offset: 0,
+            backing_file_size: 0,
+            cluster_bits,
+            size: 1 << 20,
+            crypt_method: 0,
+            l1_size: 1,
+            l1_table_offset: 3 << cluster_bits,
+            refcount_table_offset: 1 << cluster_bits,
+            refcount_table_clusters: 1,
+            nb_snapshots: 0,
+            snapshots_offset: 0,
+            incompatible_features: 0,
+            compatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: 4,
+            header_length: 104,
+            compression_type: 0,
+            tail: Vec::new(),
+        }
+    }
+
+    // Writes the header followed by `extensions` and reads the extensions back
+    fn round_trip(
+        header: &Qcow2Header,
+        extensions: &[u8],
+        name: &str,
+    ) -> io::Result<Vec<HeaderExtension>> {
+        let tmp = TempFile::new(name);
+        let file = File::create_new(tmp.path())?;
+        header.write(&file)?;
+        file.write_all_at(extensions, header.header_length as u64)?;
+        HeaderExtension::read_all(&File::open(tmp.path())?, header)
+    }
+
+    #[test]
+    fn extensions_round_trip() {
+        let extensions = vec![
+            HeaderExtension::BackingFileFormat("qcow2".to_string()),
+            HeaderExtension::FeatureNameTable(vec![
+                FeatureName {
+                    feature_type: FeatureType::Incompatible,
+                    bit: 0,
+                    name: "dirty bit".to_string(),
+                },
+                FeatureName {
+                    feature_type: FeatureType::Unknown(7),
+                    bit: 63,
+                    name: "x".repeat(46),
+                },
+            ]),
+            HeaderExtension::Bitmaps {
+                nb_bitmaps: 2,
+                reserved: 0xdead_beef,
+                bitmap_directory_size: 0x100,
+                bitmap_directory_offset: 0x50000,
+            },
+            HeaderExtension::FullDiskEncryption {
+                offset: 0x60000,
+                length: 0x1000,
+            },
+            HeaderExtension::ExternalDataFile("data.raw".to_string()),
+            HeaderExtension::Unknown {
+                ext_type: 0x1234_5678,
+                data: vec![1, 2, 3],
+            },
+        ];
+
+        let bytes = HeaderExtension::list_to_bytes(&extensions);
+        assert!(bytes.len().is_multiple_of(8));
+        let read = round_trip(&header(16), &bytes, "ext-all").unwrap();
+        assert_eq!(read, extensions);
+        // Everything is written back unchanged, reserved fields included
+        assert_eq!(HeaderExtension::list_to_bytes(&read), bytes);
+    }
+
+    #[test]
+    fn extensions_stop_at_the_end_marker() {
+        let mut bytes = HeaderExtension::list_to_bytes(&[HeaderExtension::BackingFileFormat(
+            "raw".to_string(),
+        )]);
+        bytes.extend_from_slice(&HeaderExtension::ExternalDataFile("x".to_string()).to_bytes());
+
+        let extensions = round_trip(&header(16), &bytes, "ext-end").unwrap();
+        assert_eq!(
+            extensions,
+            vec![HeaderExtension::BackingFileFormat("raw".to_string())]
+        );
+    }
+
+    #[test]
+    fn invalid_extensions_are_rejected() {
+        // Data beyond the first cluster of 512 bytes
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&0x1234_5678u32.to_be_bytes());
+        bytes.extend_from_slice(&512u32.to_be_bytes());
+        assert!(round_trip(&header(9), &bytes, "ext-long").is_err());
+
+        // Bitmaps extension that is too short
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&EXT_BITMAPS.to_be_bytes());
+        bytes.extend_from_slice(&8u32.to_be_bytes());
+        bytes.extend_from_slice(&[0u8; 8]);
+        assert!(round_trip(&header(16), &bytes, "ext-short").is_err());
+    }
+}
@@ -1,65 +1,297 @@
+use std::fs::File;
 use std::io;
 use std::os::unix::fs::FileExt;
 
+use super::QCOW2_MAGIC;
+
 // https://github.com/qemu/qemu/blob/master/docs/interop/qcow2.txt
-#[derive(Debug, Copy, Clone)]
-pub enum Qcow2Field {
-    Magic = 0,                  // 4 bytes
-    Version = 4,                // 4
-    BackingFileOffset = 8,      // 8
-    BackingFileSize = 16,       // 4
-    ClusterBits = 20,           // 4
-    Size = 24,                  // 8
-    CryptMethod = 32,           // 4
-    L1Size = 36,                // 4
-    L1TableOffset = 40,         // 8
-    RefcountTableOffset = 48,   // 8
-    RefcountTableClusters = 56, // 4
-    NbSnapshots = 60,           // 4
-    SnapshotsOffset = 64,       // 8
-    // Only for version >= 3:
-    IncompatibleFeatures = 72, // 8
-    CompatibleFeatures = 80,   // 8
-    AutoclearFeatures = 88,    // 8
-    RefcountOrder = 96,        // 4
-    HeaderLength = 100,        // 4
+//
+// Offset of the fields:
+//   Magic = 0,                  // 4 bytes
+//   Version = 4,                // 4
+//   BackingFileOffset = 8,      // 8
+//   BackingFileSize = 16,       // 4
+//   ClusterBits = 20,           // 4
+//   Size = 24,                  // 8
+//   CryptMethod = 32,           // 4
+//   L1Size = 36,                // 4
+//   L1TableOffset = 40,         // 8
+//   RefcountTableOffset = 48,   // 8
+//   RefcountTableClusters = 56, // 4
+//   NbSnapshots = 60,           // 4
+//   SnapshotsOffset = 64,       // 8
+//   // Only for version >= 3:
+//   IncompatibleFeatures = 72,  // 8
+//   CompatibleFeatures = 80,    // 8
+//   AutoclearFeatures = 88,     // 8
+//   RefcountOrder = 96,         // 4
+//   HeaderLength = 100,         // 4
+//   // Only if header length > 104:
+//   CompressionType = 104,      // 1
+//   Padding = 105,              // up to the header length
+
+// Length of the header of version 2 that has no header length field
+const V2_HEADER_LENGTH: u32 = 72;
+// Minimal length of the header of version 3
+const V3_HEADER_LENGTH: u32 = 104;
+
+/// Header of a qcow2 image. It is read once when the image is opened and
+/// written back as a whole when a field is changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Qcow2Header {
+    pub version: u32,
+    pub backing_file_offset: u64,
+    pub backing_file_size: u32,
+    pub cluster_bits: u32,
+    pub size: u64,
+    pub crypt_method: u32,
+    pub l1_size: u32,
+    pub l1_table_offset: u64,
+    pub refcount_table_offset: u64,
+    pub refcount_table_clusters: u32,
+    pub nb_snapshots: u32,
+    pub snapshots_offset: u64,
+    // Version 2 images have no features, a refcount order of 4 and a header
+    // length of 72.
+    pub incompatible_features: u64,
+    pub compatible_features: u64,
+    pub autoclear_features: u64,
+    pub refcount_order: u32,
+    pub header_length: u32,
+    pub compression_type: u8,
+    // Bytes after the compression type up to the header length. It is the
+    // padding or fields added by a newer version of the spec that are kept
+    // as is.
+    pub tail: Vec<u8>,
+}
+
+// Big-endian integers at `offset` of the on-disk structures
+pub(super) fn be_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+pub(super) fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
 }
 
-impl Qcow2Field {
-    fn size(&self) -> usize {
-        match self {
-            Qcow2Field::Magic => 4,
-            Qcow2Field::Version => 4,
-            Qcow2Field::BackingFileOffset => 8,
-            Qcow2Field::BackingFileSize => 4,
-            Qcow2Field::ClusterBits => 4,
-            Qcow2Field::Size => 8,
-            Qcow2Field::CryptMethod => 4,
-            Qcow2Field::L1Size => 4,
-            Qcow2Field::L1TableOffset => 8,
-            Qcow2Field::RefcountTableOffset => 8,
-            Qcow2Field::RefcountTableClusters => 4,
-            Qcow2Field::NbSnapshots => 4,
-            Qcow2Field::SnapshotsOffset => 8,
-            Qcow2Field::IncompatibleFeatures => 8,
-            Qcow2Field::CompatibleFeatures => 8,
-            Qcow2Field::AutoclearFeatures => 8,
-            Qcow2Field::RefcountOrder => 4,
-            Qcow2Field::HeaderLength => 4,
+pub(super) fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+impl Qcow2Header {
+    pub fn read(file: &File) -> io::Result<Self> {
+        let mut buf = vec![0u8; V2_HEADER_LENGTH as usize];
+        file.read_exact_at(&mut buf, 0)?;
+
+        let magic = be_u32(&buf, 0);
+        if magic != QCOW2_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid magic number {:?}", magic),
+            ));
+        }
+
+        let version = be_u32(&buf, 4);
+        if version != 2 && version != 3 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unsupported version {}", version),
+            ));
+        }
+
+        let mut header = Qcow2Header {
+            version,
+            backing_file_offset: be_u64(&buf, 8),
+            backing_file_size: be_u32(&buf, 16),
+            cluster_bits: be_u32(&buf, 20),
+            size: be_u64(&buf, 24),
+            crypt_method: be_u32(&buf, 32),
+            l1_size: be_u32(&buf, 36),
+            l1_table_offset: be_u64(&buf, 40),
+            refcount_table_offset: be_u64(&buf, 48),
+            refcount_table_clusters: be_u32(&buf, 56),
+            nb_snapshots: be_u32(&buf, 60),
+            snapshots_offset: be_u64(&buf, 64),
+            incompatible_features: 0,
+            compatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: 4,
+            header_length: V2_HEADER_LENGTH,
+            compression_type: 0,
+            tail: Vec::new(),
+        };
+
+        // Sanity check, the spec only allows cluster between 512 bytes and
+        // 2 MiB.
+        if !(9..=21).contains(&header.cluster_bits) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid cluster bits {}", header.cluster_bits),
+            ));
+        }
+
+        if version == 2 {
+            return Ok(header);
+        }
+
+        let mut buf = vec![0u8; (V3_HEADER_LENGTH - V2_HEADER_LENGTH) as usize];
+        file.read_exact_at(&mut buf, V2_HEADER_LENGTH as u64)?;
+
+        header.incompatible_features = be_u64(&buf, 0);
+        header.compatible_features = be_u64(&buf, 8);
+        header.autoclear_features = be_u64(&buf, 16);
+        header.refcount_order = be_u32(&buf, 24);
+        header.header_length = be_u32(&buf, 28);
+
+        // Sanity check
+        if header.header_length < V3_HEADER_LENGTH || !header.header_length.is_multiple_of(8) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid header length {}", header.header_length),
+            ));
+        }
+
+        if header.refcount_order > 6 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid refcount order {}", header.refcount_order),
+            ));
+        }
+
+        if header.header_length > V3_HEADER_LENGTH {
+            let mut buf = vec![0u8; (header.header_length - V3_HEADER_LENGTH) as usize];
+            file.read_exact_at(&mut buf, V3_HEADER_LENGTH as u64)?;
+            header.compression_type = buf[0];
+            header.tail = buf.split_off(1);
+        }
+
+        Ok(header)
+    }
+
+    /// Returns the header as it is stored on disk.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.header_length as usize);
+
+        buf.extend_from_slice(&QCOW2_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf.extend_from_slice(&self.backing_file_offset.to_be_bytes());
+        buf.extend_from_slice(&self.backing_file_size.to_be_bytes());
+        buf.extend_from_slice(&self.cluster_bits.to_be_bytes());
+        buf.extend_from_slice(&self.size.to_be_bytes());
+        buf.extend_from_slice(&self.crypt_method.to_be_bytes());
+        buf.extend_from_slice(&self.l1_size.to_be_bytes());
+        buf.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        buf.extend_from_slice(&self.refcount_table_offset.to_be_bytes());
+        buf.extend_from_slice(&self.refcount_table_clusters.to_be_bytes());
+        buf.extend_from_slice(&self.nb_snapshots.to_be_bytes());
+        buf.extend_from_slice(&self.snapshots_offset.to_be_bytes());
+
+        if self.version == 2 {
+            return buf;
+        }
+
+        buf.extend_from_slice(&self.incompatible_features.to_be_bytes());
+        buf.extend_from_slice(&self.compatible_features.to_be_bytes());
+        buf.extend_from_slice(&self.autoclear_features.to_be_bytes());
+        buf.extend_from_slice(&self.refcount_order.to_be_bytes());
+        buf.extend_from_slice(&self.header_length.to_be_bytes());
+
+        if self.header_length > V3_HEADER_LENGTH {
+            buf.push(self.compression_type);
+            buf.extend_from_slice(&self.tail);
+        }
+
+        buf
+    }
+
+    pub fn write(&self, file: &File) -> io::Result<()> {
+        file.write_all_at(&self.to_bytes(), 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempFile;
+
+    fn v3_header() -> Qcow2Header {
+        Qcow2Header {
+            version: 3,
+            backing_file_offset: 0x200,
+            backing_file_size: 10,
+            cluster_bits: 16,
+            size: 1 << 30,
+            crypt_method: 0,
+            l1_size: 2,
+            l1_table_offset: 0x30000,
+            refcount_table_offset: 0x10000,
+            refcount_table_clusters: 1,
+            nb_snapshots: 1,
+            snapshots_offset: 0x40000,
+            incompatible_features: 1 << 3,
+            compatible_features: 0,
+            autoclear_features: 1,
+            refcount_order: 4,
+            header_length: 112,
+            compression_type: 1,
+            tail: vec![0; 7],
         }
     }
 
-    pub fn read_header(&self, file: &mut std::fs::File) -> io::Result<u64> {
-        let offset = *self as u64;
-        let mut buf = vec![0u8; self.size()];
-        file.read_exact_at(&mut buf, offset)?;
+    // Writes the header to a file and reads it back
+    fn round_trip(header: &Qcow2Header, name: &str) -> io::Result<Qcow2Header> {
+        let tmp = TempFile::new(name);
+        let file = File::create_new(tmp.path())?;
+        header.write(&file)?;
+        Qcow2Header::read(&File::open(tmp.path())?)
+    }
+
+    #[test]
+    fn v3_header_round_trips() {
+        let header = v3_header();
+        assert_eq!(header.to_bytes().len(), 112);
+        assert_eq!(round_trip(&header, "header-v3").unwrap(), header);
+    }
 
-        let res = match buf.len() {
-            4 => u32::from_be_bytes(buf.try_into().unwrap()) as u64,
-            8 => u64::from_be_bytes(buf.try_into().unwrap()),
-            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unreachable")),
+    #[test]
+    fn v2_header_round_trips() {
+        let header = Qcow2Header {
+            version: 2,
+            incompatible_features: 0,
+            autoclear_features: 0,
+            header_length: V2_HEADER_LENGTH,
+            compression_type: 0,
+            tail: Vec::new(),
+            ..v3_header()
         };
+        assert_eq!(header.to_bytes().len(), V2_HEADER_LENGTH as usize);
+        assert_eq!(round_trip(&header, "header-v2").unwrap(), header);
+    }
+
+    #[test]
+    fn invalid_headers_are_rejected() {
+        let invalid = [
+            Qcow2Header {
+                version: 4,
+                ..v3_header()
+            },
+            Qcow2Header {
+                cluster_bits: 8,
+                ..v3_header()
+            },
+            Qcow2Header {
+                refcount_order: 7,
+                ..v3_header()
+            },
+            Qcow2Header {
+                header_length: 108,
+                tail: vec![0; 3],
+                ..v3_header()
+            },
+        ];
 
-        Ok(res)
+        for (i, header) in invalid.iter().enumerate() {
+            assert!(round_trip(header, &format!("header-invalid-{}", i)).is_err());
+        }
     }
 }
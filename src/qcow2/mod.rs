@@ -1,66 +1,177 @@
+mod backing;
+mod check;
+mod commit;
+mod compact;
+mod compress;
+mod convert;
+mod create;
+mod discard;
+mod entry;
+mod extension;
 mod header;
+mod rebase;
+mod refcount;
+mod resize;
+mod snapshot;
+mod subcluster;
+mod zero;
 
-use log::{debug, error};
-use std::fs::File;
+use log::{debug, warn};
+use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
 
-use header::Qcow2Field;
+use backing::Backing;
+use entry::QCOW_OFLAG_COPIED;
 
-// Only keep fields that are not modified
+pub use check::{CheckIssue, CheckReport, ClusterUse, RepairMode};
+pub use compress::CompressionType;
+pub use create::CreateOptions;
+pub use entry::{L1Entry, L2Bitmap, L2Entry, Subcluster};
+pub use extension::{FeatureName, FeatureType, HeaderExtension};
+pub use header::Qcow2Header;
+pub use snapshot::Snapshot;
+
+const QCOW2_MAGIC: u32 = 0x514649fb;
+
+// Incompatible feature bits that we know how to handle. A dirty image has
+// refcounts that may be wrong and must be repaired before being trusted, a
+// corrupt one must not be written.
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+const INCOMPAT_DATA_FILE: u64 = 1 << 2;
+const INCOMPAT_COMPRESSION_TYPE: u64 = 1 << 3;
+const INCOMPAT_EXTL2: u64 = 1 << 4;
+const INCOMPAT_KNOWN: u64 = INCOMPAT_DIRTY
+    | INCOMPAT_CORRUPT
+    | INCOMPAT_DATA_FILE
+    | INCOMPAT_COMPRESSION_TYPE
+    | INCOMPAT_EXTL2;
+
+// Subclusters are at least 512 bytes so extended L2 entries need clusters of
+// at least 16 KiB.
+const EXTL2_MIN_CLUSTER_BITS: u32 = 14;
+
+// Maximum number of images in a backing chain, including the top one
+const MAX_BACKING_CHAIN: usize = 32;
+
+fn read_backing_file_name(file: &File, header: &Qcow2Header) -> io::Result<Option<String>> {
+    if header.backing_file_size == 0 {
+        return Ok(None);
+    }
+
+    let mut buf = vec![0u8; header.backing_file_size as usize];
+    file.read_exact_at(&mut buf, header.backing_file_offset)?;
+
+    String::from_utf8(buf).map(Some).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to convert backing file name to string: {}", e),
+        )
+    })
+}
+
+// The header is parsed once when opening the image. It is only modified
+// through the write path, as is the hint used to look for free clusters.
 pub struct Qcow2 {
+    // Backing and data file names are relative to the path of the image
+    path: PathBuf,
     file: File,
-    version: u64,
+    header: Qcow2Header,
+    extensions: Vec<HeaderExtension>,
+    backing_file: Option<String>,
+    compression_type: CompressionType,
+    writable: bool,
+    free_cluster_index: u64,
+    backing: Option<Backing>,
+    // Where data clusters are stored when it is not the image itself
+    data_file: Option<File>,
+    // Set when the image is a read-only view at an internal snapshot
+    snapshot: Option<Snapshot>,
 }
 
 impl Qcow2 {
+    /// Opens the image read-only.
     pub fn new(fname: &str) -> io::Result<Self> {
-        let mut file = File::open(fname)?;
+        Qcow2::open(fname, false)
+    }
 
-        const EXPECTED_MAGIC: u64 = 0x514649fb;
-        let magic = Qcow2Field::read_header(&Qcow2Field::Magic, &mut file)?;
+    pub fn open(fname: &str, writable: bool) -> io::Result<Self> {
+        Qcow2::open_chain(Path::new(fname), writable, Some(&mut Vec::new()), false)
+    }
+
+    /// Opens the image read-write to repair it with [`Qcow2::repair`]. A
+    /// dirty image is opened as is so only the requested repair is done.
+    pub fn open_for_repair(fname: &str) -> io::Result<Self> {
+        Qcow2::open_chain(Path::new(fname), true, None, true)
+    }
 
-        if magic != EXPECTED_MAGIC {
+    /// Opens the image without its backing file, unallocated clusters read as
+    /// zeros. It allows to fix the name of a backing file that has moved with
+    /// an unsafe rebase.
+    pub fn open_without_backing(fname: &str, writable: bool) -> io::Result<Self> {
+        Qcow2::open_chain(Path::new(fname), writable, None, false)
+    }
+
+    // `chain` holds the canonical paths of the images that have this one as
+    // backing file. It is used to detect loops in the backing chain. Without
+    // a chain the backing file is not opened. The refcounts of a dirty image
+    // opened read-write are rebuilt unless `allow_dirty` is set, to repair it
+    // with another mode.
+    fn open_chain(
+        fname: &Path,
+        writable: bool,
+        chain: Option<&mut Vec<PathBuf>>,
+        allow_dirty: bool,
+    ) -> io::Result<Self> {
+        let open_backing = chain.is_some();
+        let mut no_chain = Vec::new();
+        let chain = chain.unwrap_or(&mut no_chain);
+
+        let canonical = fs::canonicalize(fname)?;
+        if chain.contains(&canonical) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
-                format!("Invalid magic number {:?}", magic),
+                format!("Loop detected in backing chain at {:?}", canonical),
             ));
         }
 
-        let version = Qcow2Field::read_header(&Qcow2Field::Version, &mut file)?;
-
-        // Sanity check
-        // As we don't understand any bits of incompatible features we
-        // just fail as soon as one is set.
-        let incompatible_features =
-            Qcow2Field::read_header(&Qcow2Field::IncompatibleFeatures, &mut file)?;
-
-        if incompatible_features != 0 {
+        if chain.len() >= MAX_BACKING_CHAIN {
             return Err(io::Error::new(
-                io::ErrorKind::Unsupported,
+                io::ErrorKind::InvalidData,
                 format!(
-                    "Unkown bit set in incompatible_features 0x{:08x}",
-                    incompatible_features
+                    "Backing chain is longer than {} images at {:?}",
+                    MAX_BACKING_CHAIN, canonical
                 ),
             ));
         }
 
-        // Sanity check for v3 only
-        if version == 3 {
-            let header_length = Qcow2Field::read_header(&Qcow2Field::HeaderLength, &mut file)?;
+        chain.push(canonical);
 
-            // Sanity check
-            if header_length < 104 || header_length % 8 != 0 {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Invalid header length {}", header_length),
-                ));
-            }
-        }
+        let file = OpenOptions::new().read(true).write(writable).open(fname)?;
+        let header = Qcow2Header::read(&file)?;
+        let compression_type = CompressionType::try_from(header.compression_type as u64)?;
+        let extensions = HeaderExtension::read_all(&file, &header)?;
+        let backing_file = read_backing_file_name(&file, &header)?;
 
-        // Print some information before returning
-        let mut q = Qcow2 { file, version };
+        let mut q = Qcow2 {
+            path: fname.to_path_buf(),
+            file,
+            header,
+            extensions,
+            backing_file,
+            compression_type,
+            writable,
+            free_cluster_index: 0,
+            backing: None,
+            data_file: None,
+            snapshot: None,
+        };
 
+        q.check_incompatible_features()?;
+
+        // Print some information before returning
         debug!("== Qcow2 header ==");
         debug!("  header length          : {}", q.header_len());
         debug!("  backing file           : {:?}", q.backing_file());
@@ -75,8 +186,9 @@ impl Qcow2 {
         );
         debug!("  refcount table entries : {}", q.refcount_table_clusters());
 
-        if version == 3 {
+        if q.version() == 3 {
             debug!("  = Version 3 only");
+            debug!("  compression type: {:?}", q.compression_type);
             debug!(
                 "  compatible features are ignored: 0x{:08x}",
                 q.compatible_features()
@@ -87,111 +199,299 @@ impl Qcow2 {
             );
         }
 
+        for ext in q.header_extensions() {
+            debug!("  extension 0x{:08x}: {:?}", ext.ext_type(), ext);
+        }
+
         // And dump L1 entries
         // TODO: Add RPC to do
         let _ = q.get_l1_entries();
 
+        if let Some(backing_name) = q.backing_file().filter(|_| open_backing) {
+            let backing_fmt = q.backing_format().map(str::to_string);
+            q.backing = Some(Backing::open(
+                fname,
+                &backing_name,
+                backing_fmt.as_deref(),
+                chain,
+            )?);
+        }
+
+        if q.incompatible_features() & INCOMPAT_DATA_FILE != 0 {
+            q.open_data_file(fname)?;
+        }
+
+        // The refcounts of a dirty image can't be trusted to allocate
+        // clusters, they are rebuilt before it is written.
+        if q.is_dirty() {
+            if writable && !allow_dirty {
+                warn!("Image {:?} is dirty, rebuilding refcounts", fname);
+                q.repair(RepairMode::All)?;
+                if q.is_dirty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Failed to rebuild refcounts of {:?}", fname),
+                    ));
+                }
+            } else {
+                warn!("Image {:?} is dirty, its refcounts may be wrong", fname);
+            }
+        }
+
+        // We don't maintain what autoclear features describe (bitmaps and raw
+        // external data file) so the spec requires to clear them.
+        if writable && q.autoclear_features() != 0 {
+            q.header.autoclear_features = 0;
+            q.write_header()?;
+        }
+
         Ok(q)
     }
 
-    fn read_feature(&mut self, field: Qcow2Field, name: &str) -> u64 {
-        Qcow2Field::read_header(&field, &mut self.file).unwrap_or_else(|_| {
-            error!("failed to read {}", name);
-            0
-        })
+    // Fails if an incompatible feature we don't know is set or if a known one
+    // is inconsistent with the rest of the header.
+    fn check_incompatible_features(&self) -> io::Result<()> {
+        let incompatible_features = self.incompatible_features();
+
+        let unknown = incompatible_features & !INCOMPAT_KNOWN;
+        if unknown != 0 {
+            let names: Vec<String> = (0..64u8)
+                .filter(|bit| unknown & (1 << bit) != 0)
+                .map(
+                    |bit| match self.feature_name(FeatureType::Incompatible, bit) {
+                        Some(name) => format!("{} (bit {})", name, bit),
+                        None => format!("bit {}", bit),
+                    },
+                )
+                .collect();
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unsupported incompatible features: {}", names.join(", ")),
+            ));
+        }
+
+        if (self.compression_type != CompressionType::Zlib)
+            != (incompatible_features & INCOMPAT_COMPRESSION_TYPE != 0)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Compression type {:?} doesn't match incompatible features 0x{:08x}",
+                    self.compression_type, incompatible_features
+                ),
+            ));
+        }
+
+        if incompatible_features & INCOMPAT_CORRUPT != 0 {
+            if self.writable {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Image is marked corrupt, it can only be opened read-only",
+                ));
+            }
+            warn!("Image is marked corrupt, data read from it may be wrong");
+        }
+
+        if self.is_extended_l2() && self.cluster_bits() < EXTL2_MIN_CLUSTER_BITS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Extended L2 entries need clusters of at least {} bytes",
+                    1 << EXTL2_MIN_CLUSTER_BITS
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn open_data_file(&mut self, fname: &Path) -> io::Result<()> {
+        let name = self.external_data_file().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "External data file is set but its name is missing",
+            )
+        })?;
+
+        let path = backing::resolve_path(fname, name);
+        debug!("Opening external data file {:?}", path);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(self.writable)
+            .open(&path)
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Failed to open external data file {:?}: {}", path, e),
+                )
+            })?;
+
+        self.data_file = Some(file);
+        Ok(())
+    }
+
+    pub fn header(&self) -> &Qcow2Header {
+        &self.header
+    }
+
+    // Writes the header back to the disk after one of its fields has been
+    // updated.
+    fn write_header(&self) -> io::Result<()> {
+        self.header.write(&self.file)
     }
 
     pub fn version(&self) -> u64 {
-        self.version
+        self.header.version as u64
     }
 
-    pub fn backing_file(&mut self) -> Option<String> {
-        let offset = self.read_feature(Qcow2Field::BackingFileOffset, "backing file offset");
-        let sz = self.read_feature(Qcow2Field::BackingFileSize, "backing file size");
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
 
-        if sz == 0 {
-            return None;
+    fn check_writable(&self) -> io::Result<()> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Image is opened read-only",
+            ))
         }
+    }
 
-        let mut buf = vec![0u8; sz as usize];
-        let _bytes_read = match self.file.read_at(&mut buf, offset) {
-            Ok(n) => n,
-            Err(e) => {
-                error!("Failed to read backing file name: {}", e);
-                return None;
-            }
-        };
+    pub fn backing_file(&self) -> Option<String> {
+        self.backing_file.clone()
+    }
 
-        let filename = match String::from_utf8(buf) {
-            Ok(f) => f,
-            Err(e) => {
-                error!("Failed to convert backing file name to string: {}", e);
-                return None;
-            }
-        };
+    pub fn header_extensions(&self) -> &[HeaderExtension] {
+        &self.extensions
+    }
 
-        Some(filename)
+    pub fn backing_format(&self) -> Option<&str> {
+        self.extensions.iter().find_map(|ext| match ext {
+            HeaderExtension::BackingFileFormat(fmt) => Some(fmt.as_str()),
+            _ => None,
+        })
     }
 
-    pub fn cluster_size(&mut self) -> usize {
-        let cluster_bits = self.read_feature(Qcow2Field::ClusterBits, "cluster bits");
-        (1 << cluster_bits) as usize
+    pub fn external_data_file(&self) -> Option<&str> {
+        self.extensions.iter().find_map(|ext| match ext {
+            HeaderExtension::ExternalDataFile(name) => Some(name.as_str()),
+            _ => None,
+        })
     }
 
-    pub fn virtual_size(&mut self) -> u64 {
-        self.read_feature(Qcow2Field::Size, "size")
+    /// Returns the name of a feature bit as found in the feature name table.
+    pub fn feature_name(&self, feature_type: FeatureType, bit: u8) -> Option<&str> {
+        self.extensions.iter().find_map(|ext| match ext {
+            HeaderExtension::FeatureNameTable(names) => names
+                .iter()
+                .find(|f| f.feature_type == feature_type && f.bit == bit)
+                .map(|f| f.name.as_str()),
+            _ => None,
+        })
     }
 
-    pub fn crypto_method(&mut self) -> u64 {
-        self.read_feature(Qcow2Field::CryptMethod, "crypto method")
+    pub fn compression_type(&self) -> CompressionType {
+        self.compression_type
     }
 
-    pub fn l1_size(&mut self) -> u64 {
-        self.read_feature(Qcow2Field::L1Size, "L1 size")
+    pub fn cluster_size(&self) -> usize {
+        1 << self.header.cluster_bits
     }
 
-    pub fn l1_table_offset(&mut self) -> u64 {
-        self.read_feature(Qcow2Field::L1TableOffset, "L1 table offset")
+    fn cluster_bits(&self) -> u32 {
+        self.header.cluster_bits
     }
 
-    pub fn refcount_table_offset(&mut self) -> u64 {
-        self.read_feature(Qcow2Field::RefcountTableOffset, "refcount table offset")
+    pub fn is_extended_l2(&self) -> bool {
+        self.header.incompatible_features & INCOMPAT_EXTL2 != 0
     }
 
-    pub fn refcount_table_clusters(&mut self) -> u64 {
-        self.read_feature(Qcow2Field::RefcountTableClusters, "refcount table clusters")
+    // Extended L2 entries are followed by the bitmap of the subclusters
+    fn l2_entry_size(&self) -> u64 {
+        if self.is_extended_l2() { 16 } else { 8 }
     }
 
-    pub fn nb_snapshots(&mut self) -> u64 {
-        self.read_feature(Qcow2Field::NbSnapshots, "number of snapshots")
+    // L2 tables are one cluster
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() as u64 / self.l2_entry_size()
     }
 
-    pub fn snapshots_offset(&mut self) -> u64 {
-        self.read_feature(Qcow2Field::SnapshotsOffset, "snapshots offset")
+    // Data clusters are stored in the external data file if there is one
+    fn data_file(&self) -> &File {
+        self.data_file.as_ref().unwrap_or(&self.file)
     }
 
-    pub fn incompatible_features(&mut self) -> u64 {
-        self.read_feature(Qcow2Field::IncompatibleFeatures, "incompatible features")
+    pub fn virtual_size(&self) -> u64 {
+        match &self.snapshot {
+            Some(snapshot) => snapshot.disk_size.unwrap_or(self.header.size),
+            None => self.header.size,
+        }
     }
 
-    pub fn compatible_features(&mut self) -> u64 {
-        self.read_feature(Qcow2Field::CompatibleFeatures, "compatible features")
+    pub fn crypto_method(&self) -> u64 {
+        self.header.crypt_method as u64
     }
 
-    pub fn autoclear_features(&mut self) -> u64 {
-        self.read_feature(Qcow2Field::AutoclearFeatures, "autoclear features")
+    pub fn l1_size(&self) -> u64 {
+        match &self.snapshot {
+            Some(snapshot) => snapshot.l1_size as u64,
+            None => self.header.l1_size as u64,
+        }
     }
 
-    pub fn refcount_width(&mut self) -> u64 {
-        let order = self.read_feature(Qcow2Field::RefcountOrder, "refcount order");
-        1 << order
+    pub fn l1_table_offset(&self) -> u64 {
+        match &self.snapshot {
+            Some(snapshot) => snapshot.l1_table_offset,
+            None => self.header.l1_table_offset,
+        }
     }
 
-    pub fn header_len(&mut self) -> u64 {
-        self.read_feature(Qcow2Field::HeaderLength, "header length")
+    pub fn refcount_table_offset(&self) -> u64 {
+        self.header.refcount_table_offset
     }
 
-    pub fn get_l1_entries(&mut self) -> Vec<(usize, u64)> {
+    pub fn refcount_table_clusters(&self) -> u64 {
+        self.header.refcount_table_clusters as u64
+    }
+
+    pub fn nb_snapshots(&self) -> u64 {
+        self.header.nb_snapshots as u64
+    }
+
+    pub fn snapshots_offset(&self) -> u64 {
+        self.header.snapshots_offset
+    }
+
+    pub fn incompatible_features(&self) -> u64 {
+        self.header.incompatible_features
+    }
+
+    /// True if the refcounts may be wrong because the image was not closed
+    /// cleanly.
+    pub fn is_dirty(&self) -> bool {
+        self.header.incompatible_features & INCOMPAT_DIRTY != 0
+    }
+
+    pub fn compatible_features(&self) -> u64 {
+        self.header.compatible_features
+    }
+
+    pub fn autoclear_features(&self) -> u64 {
+        self.header.autoclear_features
+    }
+
+    pub fn refcount_width(&self) -> u64 {
+        1 << self.header.refcount_order
+    }
+
+    pub fn header_len(&self) -> u64 {
+        self.header.header_length as u64
+    }
+
+    pub fn get_l1_entries(&self) -> Vec<(usize, u64)> {
         let l1_off = self.l1_table_offset();
         let l1_sz = self.l1_size() as usize;
 
@@ -214,66 +514,628 @@ impl Qcow2 {
         entries
     }
 
-    pub fn read_guest_cluster(&mut self, n: u64) -> Vec<u8> {
+    // Fills `data` from the backing file if any, otherwise unallocated
+    // clusters are read as zeros.
+    fn read_backing_cluster(&self, n: u64, data: &mut [u8]) -> io::Result<()> {
+        let offset = n * data.len() as u64;
+        match self.backing.as_ref() {
+            Some(backing) => backing.read_exact_at(data, offset),
+            None => {
+                data.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    // Returns true when the metadata tells that guest cluster N reads as
+    // zeros, false when its data must be read to know.
+    pub(super) fn cluster_reads_as_zeros(&self, n: u64) -> io::Result<bool> {
+        let cluster_sz = self.cluster_size() as u64;
+
+        match self.l2_entry_and_bitmap(n)? {
+            (L2Entry::Zero | L2Entry::ZeroPreallocated { .. }, None) => Ok(true),
+            (L2Entry::Unallocated, None) => self.backing_reads_as_zeros(n * cluster_sz, cluster_sz),
+            (L2Entry::Normal { .. } | L2Entry::Compressed { .. }, None) => Ok(false),
+            (L2Entry::Compressed { .. }, Some(_)) => Ok(false),
+            // Subclusters are either zeros, unallocated or allocated
+            (_, Some(bitmap)) => {
+                if bitmap.zero() == u32::MAX {
+                    Ok(true)
+                } else if bitmap.allocated() == 0 {
+                    self.backing_reads_as_zeros(n * cluster_sz, cluster_sz)
+                } else {
+                    Ok(false)
+                }
+            }
+        }
+    }
+
+    // Same as `cluster_reads_as_zeros` for the `len` bytes of the backing
+    // file at guest `offset`.
+    fn backing_reads_as_zeros(&self, offset: u64, len: u64) -> io::Result<bool> {
+        match self.backing.as_ref() {
+            Some(backing) => backing.reads_as_zeros(offset, len),
+            None => Ok(true),
+        }
+    }
+
+    // Same as `cluster_reads_as_zeros` for the `len` bytes at guest `offset`,
+    // what is beyond the virtual size reads as zeros.
+    pub(super) fn range_reads_as_zeros(&self, offset: u64, len: u64) -> io::Result<bool> {
+        let cluster_sz = self.cluster_size() as u64;
+        let end = offset.saturating_add(len).min(self.virtual_size());
+        if offset >= end {
+            return Ok(true);
+        }
+
+        for n in offset / cluster_sz..end.div_ceil(cluster_sz) {
+            if !self.cluster_reads_as_zeros(n)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_compressed_cluster(
+        &self,
+        host_offset: u64,
+        size: u64,
+        data: &mut [u8],
+    ) -> io::Result<()> {
+        debug!(
+            "Read {} bytes of compressed data at 0x{:016x}",
+            size, host_offset
+        );
+
+        // The sector count is an upper bound so the data can end before
+        let mut compressed = vec![0u8; size as usize];
+        let n = self.file.read_at(&mut compressed, host_offset)?;
+
+        compress::decompress(self.compression_type, &compressed[..n], data).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Failed to decompress cluster at 0x{:016x}: {}",
+                    host_offset, e
+                ),
+            )
+        })
+    }
+
+    /// Returns the entry of the L1 table at the given index.
+    pub fn l1_entry(&self, l1_index: u64) -> io::Result<L1Entry> {
+        let l1_size = self.l1_size();
+        if l1_index >= l1_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("L1 index {} is beyond L1 size {}", l1_index, l1_size),
+            ));
+        }
+
+        let offset = self.l1_table_offset() + l1_index * 8;
+        let raw = self.read_entry(offset).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read L1 entry at 0x{:016x}: {}", offset, e),
+            )
+        })?;
+
+        L1Entry::parse(raw, self.cluster_size() as u64)
+    }
+
+    /// Returns the L2 entry that maps guest cluster N. Guest clusters that are
+    /// not covered by an L2 table are unallocated.
+    pub fn l2_entry(&self, n: u64) -> io::Result<L2Entry> {
+        self.l2_entry_and_bitmap(n).map(|(entry, _)| entry)
+    }
+
+    /// Returns the bitmap of the subclusters of guest cluster N, only images
+    /// with extended L2 entries have one.
+    pub fn l2_bitmap(&self, n: u64) -> io::Result<Option<L2Bitmap>> {
+        self.l2_entry_and_bitmap(n).map(|(_, bitmap)| bitmap)
+    }
+
+    fn l2_entry_and_bitmap(&self, n: u64) -> io::Result<(L2Entry, Option<L2Bitmap>)> {
+        let l2_entries = self.l2_entries();
+        let l1_index = n / l2_entries;
+        let l2_index = n % l2_entries;
+
+        debug!(
+            "Guest cluster {} is at L1[{}] and L2[{}]",
+            n, l1_index, l2_index
+        );
+
+        let l2_offset = match self.l1_entry(l1_index)? {
+            L1Entry::Unallocated if self.is_extended_l2() => {
+                return Ok((L2Entry::Unallocated, Some(L2Bitmap(0))));
+            }
+            L1Entry::Unallocated => return Ok((L2Entry::Unallocated, None)),
+            L1Entry::L2Table { offset, .. } => offset,
+        };
+
+        let offset = l2_offset + l2_index * self.l2_entry_size();
+        self.read_l2_entry(offset).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read L2 entry at 0x{:016x}: {}", offset, e),
+            )
+        })
+    }
+
+    // Reads and parses the L2 entry stored at `offset`
+    fn read_l2_entry(&self, offset: u64) -> io::Result<(L2Entry, Option<L2Bitmap>)> {
+        let mut bytes = [0u8; 16];
+        let len = self.l2_entry_size() as usize;
+        self.file.read_exact_at(&mut bytes[..len], offset)?;
+        self.parse_l2_entry(&bytes[..len])
+    }
+
+    // `bytes` is an L2 entry as stored on disk, 8 or 16 bytes long
+    fn parse_l2_entry(&self, bytes: &[u8]) -> io::Result<(L2Entry, Option<L2Bitmap>)> {
+        let raw = u64::from_be_bytes(bytes[..8].try_into().unwrap());
+        if self.is_extended_l2() {
+            let bitmap = u64::from_be_bytes(bytes[8..16].try_into().unwrap());
+            let (entry, bitmap) = L2Entry::parse_extended(raw, bitmap, self.cluster_bits())?;
+            Ok((entry, Some(bitmap)))
+        } else {
+            Ok((
+                L2Entry::parse(raw, self.cluster_bits(), self.version())?,
+                None,
+            ))
+        }
+    }
+
+    // Reads a whole L1 table
+    fn read_l1_table(&self, l1_offset: u64, l1_size: u64) -> io::Result<Vec<L1Entry>> {
+        let mut table = vec![0u8; l1_size as usize * 8];
+        self.file.read_exact_at(&mut table, l1_offset)?;
+        table
+            .chunks_exact(8)
+            .map(|chunk| {
+                let raw = u64::from_be_bytes(chunk.try_into().unwrap());
+                L1Entry::parse(raw, self.cluster_size() as u64)
+            })
+            .collect()
+    }
+
+    // Reads a whole L2 table
+    fn read_l2_table(&self, l2_offset: u64) -> io::Result<Vec<(L2Entry, Option<L2Bitmap>)>> {
+        let mut table = vec![0u8; self.cluster_size()];
+        self.file.read_exact_at(&mut table, l2_offset)?;
+        table
+            .chunks_exact(self.l2_entry_size() as usize)
+            .map(|chunk| self.parse_l2_entry(chunk))
+            .collect()
+    }
+
+    fn write_l2_entry(
+        &self,
+        offset: u64,
+        entry: L2Entry,
+        bitmap: Option<L2Bitmap>,
+    ) -> io::Result<()> {
+        self.write_entry(offset, entry.to_raw(self.cluster_bits()))?;
+        match bitmap {
+            Some(bitmap) => self.write_entry(offset + 8, bitmap.0),
+            None => Ok(()),
+        }
+    }
+
+    pub fn read_guest_cluster(&self, n: u64) -> io::Result<Vec<u8>> {
         // Read the data corresponding to guest cluster N
         let cluster_sz = self.cluster_size();
         let mut data = vec![0u8; cluster_sz];
 
         debug!("Reading data from guest cluster {}", n);
-        let l1_entries_per_cluster = cluster_sz / 8; // L1 entries are 8 bytes and the size of L1 is one cluster
-        // l1 entries per cluster is the same as L2 entries because L2 is a cluster size and addresses are 8 bytes.
-        let l1_index = n / l1_entries_per_cluster as u64;
-        let l2_index = n % l1_entries_per_cluster as u64;
+        let (l2_entry, bitmap) = self.l2_entry_and_bitmap(n)?;
+        debug!("Read L2 entry: {:?} {:?}", l2_entry, bitmap);
+
+        if let Some(bitmap) = bitmap
+            && !matches!(l2_entry, L2Entry::Compressed { .. })
+        {
+            self.read_subclusters(n, l2_entry.host_offset(), bitmap, &mut data)?;
+            return Ok(data);
+        }
+
+        match l2_entry {
+            // There is no data here so look into the backing file
+            L2Entry::Unallocated => self.read_backing_cluster(n, &mut data)?,
+            L2Entry::Zero | L2Entry::ZeroPreallocated { .. } => {}
+            L2Entry::Compressed { host_offset, size } => {
+                self.read_compressed_cluster(host_offset, size, &mut data)?
+            }
+            L2Entry::Normal { host_offset, .. } => {
+                let n = self.data_file().read_at(&mut data, host_offset)?;
+                debug!("Read {} bytes of data", n);
+                data.truncate(n);
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Reads `len` bytes at `guest_offset`. The read stops at the virtual
+    /// size of the image so the returned data can be shorter than `len`.
+    pub fn read_at(&self, guest_offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let end = guest_offset
+            .saturating_add(len as u64)
+            .min(self.virtual_size());
+        let cluster_sz = self.cluster_size();
+
+        let mut buf = Vec::with_capacity(end.saturating_sub(guest_offset) as usize);
+        let mut offset = guest_offset;
+
+        while offset < end {
+            let in_cluster = (offset % cluster_sz as u64) as usize;
+            let len = ((end - offset) as usize).min(cluster_sz - in_cluster);
+
+            // The data of the last cluster of the file can be truncated
+            let mut data = self.read_guest_cluster(offset / cluster_sz as u64)?;
+            data.resize(cluster_sz, 0);
+            buf.extend_from_slice(&data[in_cluster..in_cluster + len]);
+
+            offset += len as u64;
+        }
+
+        Ok(buf)
+    }
+
+    /// Writes `buf` at `guest_offset`. New host clusters and L2 tables are
+    /// allocated when needed and shared clusters are copied before being
+    /// modified.
+    pub fn write_at(&mut self, guest_offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.check_writable()?;
+
+        let virtual_size = self.virtual_size();
+        match guest_offset.checked_add(buf.len() as u64) {
+            Some(end) if end <= virtual_size => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Write of {} bytes at 0x{:016x} is beyond virtual size {}",
+                        buf.len(),
+                        guest_offset,
+                        virtual_size
+                    ),
+                ));
+            }
+        }
+
+        let cluster_sz = self.cluster_size();
+        let mut written = 0;
+
+        while written < buf.len() {
+            let offset = guest_offset + written as u64;
+            let in_cluster = (offset % cluster_sz as u64) as usize;
+            let len = (buf.len() - written).min(cluster_sz - in_cluster);
+
+            self.write_guest_cluster(
+                offset / cluster_sz as u64,
+                in_cluster,
+                &buf[written..written + len],
+            )?;
+
+            written += len;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn read_entry(&self, offset: u64) -> io::Result<u64> {
+        let mut bytes: [u8; 8] = [0u8; 8];
+        self.file.read_exact_at(&mut bytes, offset)?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn write_entry(&self, offset: u64, entry: u64) -> io::Result<()> {
+        self.file.write_all_at(&entry.to_be_bytes(), offset)
+    }
+
+    // Write `data` at `in_cluster` bytes from the beginning of guest cluster N.
+    fn write_guest_cluster(&mut self, n: u64, in_cluster: usize, data: &[u8]) -> io::Result<()> {
+        let cluster_sz = self.cluster_size();
+        let cluster_bits = self.cluster_bits();
+        let l2_offset = self.get_writable_l2_table(n)?;
+        let l2_entry_offset = l2_offset + (n % self.l2_entries()) * self.l2_entry_size();
+
+        let (l2_entry, bitmap) = self.read_l2_entry(l2_entry_offset)?;
+
+        // With extended L2 entries the whole cluster is allocated on write so
+        // the bitmap is only checked when the cluster is reused.
+        let bitmap_full = bitmap.is_none_or(|b| b == L2Bitmap::ALL_ALLOCATED);
+        let new_bitmap = bitmap.map(|_| L2Bitmap::ALL_ALLOCATED);
+
+        // If the host cluster is only referenced by us we can write in place.
+        // The COPIED flag may be missing even if the refcount is 1, in this
+        // case just fix the flag.
+        match l2_entry {
+            L2Entry::Normal {
+                host_offset,
+                copied,
+            } if copied || self.get_refcount(host_offset >> cluster_bits)? == 1 => {
+                let entry = L2Entry::Normal {
+                    host_offset,
+                    copied: true,
+                };
+
+                if !bitmap_full {
+                    return self.write_subclusters_in_place(
+                        n,
+                        l2_entry_offset,
+                        host_offset,
+                        in_cluster,
+                        data,
+                    );
+                }
+
+                debug!(
+                    "Write {} bytes in place at 0x{:016x}",
+                    data.len(),
+                    host_offset + in_cluster as u64
+                );
+                self.data_file()
+                    .write_all_at(data, host_offset + in_cluster as u64)?;
+                if !copied {
+                    self.write_l2_entry(l2_entry_offset, entry, None)?;
+                }
+                return Ok(());
+            }
+            L2Entry::ZeroPreallocated {
+                host_offset,
+                copied,
+            } if copied || self.get_refcount(host_offset >> cluster_bits)? == 1 => {
+                // The preallocated cluster is reused but it must be zeroed
+                let mut cluster = vec![0u8; cluster_sz];
+                cluster[in_cluster..in_cluster + data.len()].copy_from_slice(data);
+                self.data_file().write_all_at(&cluster, host_offset)?;
+
+                let entry = L2Entry::Normal {
+                    host_offset,
+                    copied: true,
+                };
+                self.write_l2_entry(l2_entry_offset, entry, None)?;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        // We need a new cluster filled with the current content of the guest
+        // cluster and the new data.
+        let mut cluster = if data.len() == cluster_sz {
+            Vec::with_capacity(cluster_sz)
+        } else {
+            self.read_guest_cluster(n)?
+        };
+        cluster.resize(cluster_sz, 0);
+        cluster[in_cluster..in_cluster + data.len()].copy_from_slice(data);
+
+        let new_offset = self.alloc_clusters(1)?;
+        self.data_file().write_all_at(&cluster, new_offset)?;
+
+        let entry = L2Entry::Normal {
+            host_offset: new_offset,
+            copied: true,
+        };
+        self.write_l2_entry(l2_entry_offset, entry, new_bitmap)?;
 
         debug!(
-            "Guest cluster {} is at L1[{}] and L2[{}]",
-            n, l1_index, l2_index
+            "Guest cluster {} is now at host offset 0x{:016x}",
+            n, new_offset
         );
 
-        let mut bytes: [u8; 8] = [0u8; 8];
-        let l1_offset = self.l1_table_offset();
+        // Drop our reference to the previous host clusters. Compressed data
+        // holds a reference on every host cluster it spans.
+        if let Some((first, last)) = l2_entry.host_clusters(cluster_bits) {
+            for cluster in first..=last {
+                self.decrement_refcount(cluster)?;
+            }
+        }
 
-        self.file
-            .read_exact_at(&mut bytes, l1_offset + l1_index * 8)
-            .unwrap_or_else(|_| {
-                panic!("Failed to read L1 entry at 0x{:016x}", l1_offset + l1_index)
-            });
+        Ok(())
+    }
+
+    // Returns the offset of the L2 table that maps guest cluster N. The table
+    // is allocated if needed and copied if it is shared with a snapshot.
+    fn get_writable_l2_table(&mut self, n: u64) -> io::Result<u64> {
+        let cluster_sz = self.cluster_size();
+        let cluster_bits = self.cluster_bits();
+        let l1_index = n / self.l2_entries();
 
-        let l1_entry = u64::from_be_bytes(bytes);
-        debug!("Read L1 entry 0x{:016x}", l1_entry);
+        let l1_entry = self.l1_entry(l1_index)?;
+        let l1_entry_offset = self.l1_table_offset() + l1_index * 8;
 
-        if l1_entry == 0 {
-            // If entry is null it means there is no data so just returns.
-            return data;
+        if let L1Entry::L2Table { offset, copied } = l1_entry {
+            if copied {
+                return Ok(offset);
+            }
+
+            if self.get_refcount(offset >> cluster_bits)? == 1 {
+                let entry = L1Entry::L2Table {
+                    offset,
+                    copied: true,
+                };
+                self.write_entry(l1_entry_offset, entry.to_raw())?;
+                return Ok(offset);
+            }
         }
 
-        let l2_offset = 0x7FFF_FFFF_FFFF_FFFF & l1_entry;
-        debug!("Read L1 entry and get L2 offset 0x{:016x}", l2_offset);
+        // Allocate a new L2 table. If there was a shared one we start from a
+        // copy of it. As the data clusters are still shared their COPIED
+        // flag is cleared, the bitmap of extended entries is kept.
+        let mut table = vec![0u8; cluster_sz];
+        if let L1Entry::L2Table { offset, .. } = l1_entry {
+            self.file.read_exact_at(&mut table, offset)?;
+            for chunk in table.chunks_exact_mut(self.l2_entry_size() as usize) {
+                let entry = u64::from_be_bytes(chunk[..8].try_into().unwrap());
+                chunk[..8].copy_from_slice(&(entry & !QCOW_OFLAG_COPIED).to_be_bytes());
+            }
+        }
 
-        self.file
-            .read_exact_at(&mut bytes, l2_offset + l2_index * 8)
-            .unwrap_or_else(|_| {
-                panic!(
-                    "Failed to read L1 entry at 0x{:016x}",
-                    l2_offset + l2_index * 8
-                )
-            });
+        let new_l2_offset = self.alloc_clusters(1)?;
+        self.file.write_all_at(&table, new_l2_offset)?;
 
-        let l2_entry = u64::from_be_bytes(bytes);
-        debug!("Read L2 entry: 0x{:016x}", l2_entry);
+        let entry = L1Entry::L2Table {
+            offset: new_l2_offset,
+            copied: true,
+        };
+        self.write_entry(l1_entry_offset, entry.to_raw())?;
 
-        if l2_entry == 0 {
-            return data;
+        debug!(
+            "L1[{}] now points to L2 table at 0x{:016x}",
+            l1_index, new_l2_offset
+        );
+
+        if let L1Entry::L2Table { offset, .. } = l1_entry {
+            self.decrement_refcount(offset >> cluster_bits)?;
         }
 
-        let data_offset = 0x7FFF_FFFF_FFFF_FFFF & l2_entry;
-        let n = self
-            .file
-            .read_at(&mut data, data_offset)
-            .expect("Failed to read data for guest cluster");
+        Ok(new_l2_offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CreateOptions, INCOMPAT_DIRTY, L1Entry, L2Entry, Qcow2};
+    use crate::testutil::TempFile;
+
+    fn create(path: &str, virtual_size: u64, cluster_bits: u32) -> Qcow2 {
+        let opts = CreateOptions {
+            virtual_size,
+            cluster_bits,
+            ..Default::default()
+        };
+        Qcow2::create(path, opts).unwrap()
+    }
+
+    fn assert_clean(q: &Qcow2) {
+        let report = q.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn unaligned_write_across_clusters() {
+        let tmp = TempFile::new("write-unaligned");
+        let mut q = create(tmp.path(), 1 << 20, 16);
+
+        // Starts in the middle of cluster 1 and ends in the middle of 3
+        let data: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+        q.write_at(100_000, &data).unwrap();
+
+        let mut expected = vec![0; 1 << 20];
+        expected[100_000..250_000].copy_from_slice(&data);
+        assert!(q.read_at(0, 1 << 20).unwrap() == expected);
+        assert_eq!(q.l2_entry(0).unwrap(), L2Entry::Unallocated);
+        for n in 1..4 {
+            assert!(matches!(q.l2_entry(n).unwrap(), L2Entry::Normal { .. }));
+        }
+        assert_clean(&q);
+    }
+
+    #[test]
+    fn write_allocates_l2_tables_across_l1_entries() {
+        let tmp = TempFile::new("write-l1-boundary");
+        // An L2 table of 4 KiB clusters maps 2 MiB
+        let mut q = create(tmp.path(), 8 << 20, 12);
+        let l2_span = 512 << 12;
+
+        q.write_at(l2_span - 1000, &[5; 3000]).unwrap();
+
+        assert!(matches!(q.l1_entry(0).unwrap(), L1Entry::L2Table { .. }));
+        assert!(matches!(q.l1_entry(1).unwrap(), L1Entry::L2Table { .. }));
+        assert_eq!(q.l1_entry(2).unwrap(), L1Entry::Unallocated);
+        assert_eq!(q.read_at(l2_span - 1000, 3000).unwrap(), vec![5; 3000]);
+        assert_eq!(q.read_at(l2_span - 2000, 1000).unwrap(), vec![0; 1000]);
+        assert_eq!(q.read_at(l2_span + 2000, 1000).unwrap(), vec![0; 1000]);
+        assert_clean(&q);
+    }
+
+    #[test]
+    fn write_copies_clusters_shared_with_a_snapshot() {
+        let tmp = TempFile::new("write-cow");
+        let mut q = create(tmp.path(), 1 << 20, 16);
+        let cluster_bits = q.cluster_bits();
+
+        q.write_at(0, &[1; 1 << 16]).unwrap();
+        q.create_snapshot("snap").unwrap();
+        let old_offset = match q.l2_entry(0).unwrap() {
+            L2Entry::Normal {
+                host_offset,
+                copied: false,
+            } => host_offset,
+            entry => panic!("Unexpected entry {:?}", entry),
+        };
+        assert_eq!(q.get_refcount(old_offset >> cluster_bits).unwrap(), 2);
+
+        q.write_at(1000, &[2; 100]).unwrap();
+
+        let new_offset = match q.l2_entry(0).unwrap() {
+            L2Entry::Normal {
+                host_offset,
+                copied: true,
+            } => host_offset,
+            entry => panic!("Unexpected entry {:?}", entry),
+        };
+        assert_ne!(new_offset, old_offset);
+        assert_eq!(q.get_refcount(old_offset >> cluster_bits).unwrap(), 1);
+        assert_eq!(q.get_refcount(new_offset >> cluster_bits).unwrap(), 1);
+
+        let mut expected = vec![1; 1 << 16];
+        expected[1000..1100].fill(2);
+        assert_eq!(q.read_at(0, 1 << 16).unwrap(), expected);
+        assert_clean(&q);
+        drop(q);
+
+        let snapshot = Qcow2::open_snapshot(tmp.path(), "snap").unwrap();
+        assert_eq!(snapshot.read_at(0, 1 << 16).unwrap(), vec![1; 1 << 16]);
+    }
+
+    // Leaks a cluster and drops the refcount of the first data cluster, then
+    // marks the image dirty as if it was not closed cleanly.
+    fn make_dirty(path: &str) {
+        let opts = CreateOptions {
+            virtual_size: 1 << 20,
+            ..Default::default()
+        };
+        let mut q = Qcow2::create(path, opts).unwrap();
+        q.write_at(0, &[1; 1 << 16]).unwrap();
+        q.alloc_clusters(1).unwrap();
+        let host_offset = q.l2_entry(0).unwrap().host_offset().unwrap();
+        q.set_refcount(host_offset >> q.cluster_bits(), 0).unwrap();
+
+        q.header.incompatible_features |= INCOMPAT_DIRTY;
+        q.write_header().unwrap();
+        // The COPIED flag of the data cluster doesn't match its refcount either
+        let report = q.check().unwrap();
+        assert_eq!((report.leaks, report.corruptions), (1, 2));
+    }
+
+    #[test]
+    fn dirty_image_is_repaired_when_opened_writable() {
+        let tmp = TempFile::new("open-dirty");
+        make_dirty(tmp.path());
+
+        // Read-only and repair opens leave the image as is
+        let q = Qcow2::open(tmp.path(), false).unwrap();
+        assert!(q.is_dirty());
+        drop(q);
+        let q = Qcow2::open_for_repair(tmp.path()).unwrap();
+        assert!(q.is_dirty());
+        assert!(!q.check().unwrap().is_clean());
+        drop(q);
+
+        let q = Qcow2::open(tmp.path(), true).unwrap();
+        assert!(!q.is_dirty());
+        let report = q.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+        assert_eq!(q.read_at(0, 1 << 16).unwrap(), vec![1; 1 << 16]);
+        drop(q);
 
-        debug!("Read {} bytes of data", n);
-        data.truncate(n);
-        data
+        // The cleared dirty bit is on disk
+        assert!(!Qcow2::new(tmp.path()).unwrap().is_dirty());
     }
 }
@@ -0,0 +1,207 @@
+use log::{debug, warn};
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use super::backing::Backing;
+use super::{HeaderExtension, L2Bitmap, L2Entry, Qcow2, Subcluster};
+
+// Returns true when some data of the guest cluster is read from the backing
+// file.
+fn reads_backing(entry: &L2Entry, bitmap: Option<L2Bitmap>) -> bool {
+    match (entry, bitmap) {
+        (L2Entry::Compressed { .. }, _) => false,
+        (_, Some(bitmap)) => {
+            (0..L2Bitmap::SUBCLUSTERS).any(|i| bitmap.subcluster(i) == Subcluster::Unallocated)
+        }
+        (L2Entry::Unallocated, None) => true,
+        _ => false,
+    }
+}
+
+impl Qcow2 {
+    /// Changes the backing file of the image, `None` removes it. In safe mode
+    /// the clusters read from the backing file whose data differs between
+    /// the old and the new backing files are first copied into the image so
+    /// the guest sees the same data. In unsafe mode only the header is
+    /// rewritten. The format of the new backing file is guessed in safe mode
+    /// when it is not given. `progress` is called with the guest bytes
+    /// processed and the virtual size. Returns the number of clusters copied.
+    pub fn rebase<F: FnMut(u64, u64)>(
+        &mut self,
+        backing: Option<&str>,
+        backing_fmt: Option<&str>,
+        safe: bool,
+        mut progress: F,
+    ) -> io::Result<u64> {
+        self.check_writable()?;
+
+        let new_backing = match backing {
+            Some(name) => {
+                let mut chain = vec![fs::canonicalize(&self.path)?];
+                match Backing::open(&self.path, name, backing_fmt, &mut chain) {
+                    Ok(backing) => Some(backing),
+                    Err(e) if !safe => {
+                        warn!("New backing file {} can't be opened: {}", name, e);
+                        None
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            None => None,
+        };
+
+        let backing_fmt = match (backing_fmt, &new_backing) {
+            (Some(fmt), _) => Some(fmt),
+            (None, Some(Backing::Raw(_))) if safe => Some("raw"),
+            (None, Some(Backing::Qcow2(_))) if safe => Some("qcow2"),
+            (None, _) => None,
+        };
+
+        let mut copied = 0;
+        if safe {
+            let virtual_size = self.virtual_size();
+            let cluster_sz = self.cluster_size() as u64;
+            let mut old_data = vec![0u8; cluster_sz as usize];
+            let mut new_data = vec![0u8; cluster_sz as usize];
+
+            for n in 0..virtual_size.div_ceil(cluster_sz) {
+                let (entry, bitmap) = self.l2_entry_and_bitmap(n)?;
+                let offset = n * cluster_sz;
+                let len = (virtual_size - offset).min(cluster_sz) as usize;
+                progress(offset + len as u64, virtual_size);
+
+                if !reads_backing(&entry, bitmap) {
+                    continue;
+                }
+
+                self.read_backing_cluster(n, &mut old_data)?;
+                match &new_backing {
+                    Some(backing) => backing.read_exact_at(&mut new_data, offset)?,
+                    None => new_data.fill(0),
+                }
+                if old_data[..len] == new_data[..len] {
+                    continue;
+                }
+
+                // The guest data is kept as is, allocated subclusters included
+                let data = self.read_guest_cluster(n)?;
+                self.write_at(offset, &data[..len])?;
+                copied += 1;
+            }
+
+            self.flush()?;
+        }
+
+        self.write_backing_file_header(backing, backing_fmt)?;
+        self.backing = new_backing;
+
+        debug!(
+            "Rebased onto {:?} ({:?}), {} cluster(s) copied",
+            backing, backing_fmt, copied
+        );
+        Ok(copied)
+    }
+
+    // Rewrites the first cluster with the new backing file name and format.
+    // The name is stored after the header extensions.
+    fn write_backing_file_header(
+        &mut self,
+        backing: Option<&str>,
+        backing_fmt: Option<&str>,
+    ) -> io::Result<()> {
+        let mut extensions = self.extensions.clone();
+        extensions.retain(|ext| !matches!(ext, HeaderExtension::BackingFileFormat(_)));
+        if let (Some(_), Some(fmt)) = (backing, backing_fmt) {
+            extensions.insert(0, HeaderExtension::BackingFileFormat(fmt.to_string()));
+        }
+
+        let mut header = self.header.clone();
+        let ext_bytes = HeaderExtension::list_to_bytes(&extensions);
+        let name = backing.unwrap_or_default().as_bytes();
+        let name_offset = header.header_length as u64 + ext_bytes.len() as u64;
+
+        let cluster_sz = self.cluster_size();
+        if name_offset as usize + name.len() > cluster_sz {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Backing file name {:?} doesn't fit in the first cluster",
+                    backing.unwrap_or_default()
+                ),
+            ));
+        }
+
+        (header.backing_file_offset, header.backing_file_size) = match name.len() {
+            0 => (0, 0),
+            len => (name_offset, len as u32),
+        };
+
+        // The whole first cluster belongs to the header so leftovers of the
+        // previous extensions and name are cleared.
+        let mut first_cluster = header.to_bytes();
+        first_cluster.extend_from_slice(&ext_bytes);
+        first_cluster.extend_from_slice(name);
+        first_cluster.resize(cluster_sz, 0);
+        self.file.write_all_at(&first_cluster, 0)?;
+        self.file.sync_data()?;
+
+        self.header = header;
+        self.extensions = extensions;
+        self.backing_file = backing.map(str::to_string);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::super::{CreateOptions, Qcow2};
+    use crate::testutil::TempFile;
+
+    const CLUSTER_SIZE: usize = 1 << 16;
+
+    #[test]
+    fn safe_rebase_keeps_guest_data() {
+        let old = TempFile::new("rebase-old.img");
+        fs::write(old.path(), vec![1u8; 4 * CLUSTER_SIZE]).unwrap();
+
+        // Cluster 1 differs and cluster 3 is beyond the end of the new one
+        let mut new_data = vec![1u8; 3 * CLUSTER_SIZE];
+        new_data[CLUSTER_SIZE + 3] = 9;
+        let new = TempFile::new("rebase-new.img");
+        fs::write(new.path(), &new_data).unwrap();
+
+        let tmp = TempFile::new("rebase.qcow2");
+        let opts = CreateOptions {
+            backing_file: Some(old.path().to_string()),
+            backing_fmt: Some("raw".to_string()),
+            ..Default::default()
+        };
+        let mut q = Qcow2::create(tmp.path(), opts).unwrap();
+        q.write_at(10, &[2; 100]).unwrap();
+        let expected = q.read_at(0, 4 * CLUSTER_SIZE).unwrap();
+
+        let copied = q.rebase(Some(new.path()), None, true, |_, _| {}).unwrap();
+        assert_eq!(copied, 2);
+        assert_eq!(q.backing_file().as_deref(), Some(new.path()));
+        assert_eq!(q.backing_format(), Some("raw"));
+        assert!(q.read_at(0, 4 * CLUSTER_SIZE).unwrap() == expected);
+        drop(q);
+
+        let mut q = Qcow2::open(tmp.path(), true).unwrap();
+        assert!(q.read_at(0, 4 * CLUSTER_SIZE).unwrap() == expected);
+        let report = q.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+
+        // Without backing file only cluster 2 still reads from it
+        assert_eq!(q.rebase(None, None, true, |_, _| {}).unwrap(), 1);
+        assert_eq!(q.backing_file(), None);
+        drop(q);
+
+        let q = Qcow2::new(tmp.path()).unwrap();
+        assert!(q.read_at(0, 4 * CLUSTER_SIZE).unwrap() == expected);
+        assert!(q.check().unwrap().is_clean());
+    }
+}
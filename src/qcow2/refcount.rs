@@ -0,0 +1,383 @@
+use log::debug;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use super::Qcow2;
+
+// Bits 0-8 of a refcount table entry are reserved, the remaining bits are the
+// offset of the refcount block.
+pub(super) const REFT_OFFSET_MASK: u64 = 0xFFFF_FFFF_FFFF_FE00;
+
+// Refcounts narrower than a byte are packed starting from the least
+// significant bits of each byte, wider ones are stored big endian.
+pub(super) fn read_refcount(block: &[u8], index: u64, order: u32) -> u64 {
+    let width = 1u64 << order;
+    if width < 8 {
+        let byte = block[(index * width / 8) as usize] as u64;
+        (byte >> ((index * width) % 8)) & ((1 << width) - 1)
+    } else {
+        let bytes = (width / 8) as usize;
+        let start = index as usize * bytes;
+        block[start..start + bytes]
+            .iter()
+            .fold(0, |acc, &b| (acc << 8) | b as u64)
+    }
+}
+
+pub(super) fn write_refcount(block: &mut [u8], index: u64, order: u32, value: u64) {
+    let width = 1u64 << order;
+    if width < 8 {
+        let pos = (index * width / 8) as usize;
+        let shift = (index * width) % 8;
+        let mask = (((1u16 << width) - 1) << shift) as u8;
+        block[pos] = (block[pos] & !mask) | (((value as u8) << shift) & mask);
+    } else {
+        let bytes = (width / 8) as usize;
+        let start = index as usize * bytes;
+        block[start..start + bytes].copy_from_slice(&value.to_be_bytes()[8 - bytes..]);
+    }
+}
+
+impl Qcow2 {
+    pub(super) fn refcount_order(&self) -> u32 {
+        self.header.refcount_order
+    }
+
+    /// Maximum value of a refcount for the refcount width of the image.
+    pub fn max_refcount(&self) -> u64 {
+        match self.refcount_width() {
+            64 => u64::MAX,
+            width => (1 << width) - 1,
+        }
+    }
+
+    // Number of refcounts stored in one refcount block
+    pub(super) fn refcount_block_entries(&self) -> u64 {
+        self.cluster_size() as u64 * 8 / self.refcount_width()
+    }
+
+    // Number of refcount block offsets stored in the refcount table
+    fn refcount_table_entries(&self) -> u64 {
+        self.refcount_table_clusters() * self.cluster_size() as u64 / 8
+    }
+
+    // Returns the offset of the refcount block at the given index of the
+    // refcount table or None if it is not allocated.
+    fn refcount_block_offset(&self, table_index: u64) -> io::Result<Option<u64>> {
+        if table_index >= self.refcount_table_entries() {
+            return Ok(None);
+        }
+
+        let mut bytes: [u8; 8] = [0u8; 8];
+        let table_offset = self.refcount_table_offset();
+        self.file
+            .read_exact_at(&mut bytes, table_offset + table_index * 8)?;
+
+        let block_offset = u64::from_be_bytes(bytes) & REFT_OFFSET_MASK;
+        if block_offset == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(block_offset))
+        }
+    }
+
+    // Returns where the refcount of the host cluster is stored: the index in
+    // the refcount table, the offset and length of the bytes that hold it in
+    // the refcount block and its index within these bytes.
+    fn refcount_location(&self, host_cluster: u64) -> (u64, u64, usize, u64) {
+        let width = self.refcount_width();
+        let entries = self.refcount_block_entries();
+        let index = host_cluster % entries;
+
+        if width < 8 {
+            let per_byte = 8 / width;
+            (
+                host_cluster / entries,
+                index / per_byte,
+                1,
+                index % per_byte,
+            )
+        } else {
+            (
+                host_cluster / entries,
+                index * width / 8,
+                (width / 8) as usize,
+                0,
+            )
+        }
+    }
+
+    /// Returns the refcount of the host cluster. A cluster that is not covered
+    /// by any refcount block has a refcount of 0.
+    pub fn get_refcount(&self, host_cluster: u64) -> io::Result<u64> {
+        let (table_index, offset, len, index) = self.refcount_location(host_cluster);
+
+        let block_offset = match self.refcount_block_offset(table_index)? {
+            None => return Ok(0),
+            Some(off) => off,
+        };
+
+        let mut bytes: [u8; 8] = [0u8; 8];
+        self.file
+            .read_exact_at(&mut bytes[..len], block_offset + offset)?;
+
+        Ok(read_refcount(&bytes[..len], index, self.refcount_order()))
+    }
+
+    /// Sets the refcount of the host cluster, allocating the refcount block
+    /// (and growing the refcount table) when needed.
+    pub(super) fn set_refcount(&mut self, host_cluster: u64, value: u64) -> io::Result<()> {
+        if value > self.max_refcount() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Refcount {} overflows for cluster {}", value, host_cluster),
+            ));
+        }
+
+        let (table_index, offset, len, index) = self.refcount_location(host_cluster);
+
+        let block_offset = match self.refcount_block_offset(table_index)? {
+            Some(off) => off,
+            // There is nothing to clear if the block doesn't exist
+            None if value == 0 => return Ok(()),
+            None => self.alloc_refcount_block(table_index)?,
+        };
+
+        // Entries narrower than a byte share it with their neighbours
+        let mut bytes: [u8; 8] = [0u8; 8];
+        if len == 1 {
+            self.file
+                .read_exact_at(&mut bytes[..len], block_offset + offset)?;
+        }
+        write_refcount(&mut bytes[..len], index, self.refcount_order(), value);
+        self.file
+            .write_all_at(&bytes[..len], block_offset + offset)?;
+
+        if value == 0 && host_cluster < self.free_cluster_index {
+            self.free_cluster_index = host_cluster;
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn increment_refcount(&mut self, host_cluster: u64) -> io::Result<()> {
+        let refcount = self.get_refcount(host_cluster)?;
+        self.set_refcount(host_cluster, refcount + 1)
+    }
+
+    pub(super) fn decrement_refcount(&mut self, host_cluster: u64) -> io::Result<()> {
+        let refcount = self.get_refcount(host_cluster)?;
+        if refcount == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Refcount of cluster {} is already 0", host_cluster),
+            ));
+        }
+        self.set_refcount(host_cluster, refcount - 1)
+    }
+
+    // Drops one reference to each host cluster of the `len` bytes at `offset`
+    pub(super) fn free_clusters(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        let cluster_bits = self.cluster_bits();
+        for cluster in (offset >> cluster_bits)..=((offset + len - 1) >> cluster_bits) {
+            self.decrement_refcount(cluster)?;
+        }
+        Ok(())
+    }
+
+    // Looks for `count` contiguous clusters with a refcount of 0 and returns
+    // the index of the first one. Clusters are not marked as used, it is up to
+    // the caller to set their refcount.
+    pub(super) fn find_free_clusters(&mut self, count: u64) -> io::Result<u64> {
+        let mut start = self.free_cluster_index;
+        let mut run = 0;
+
+        while run < count {
+            if self.get_refcount(start + run)? == 0 {
+                run += 1;
+            } else {
+                start += run + 1;
+                run = 0;
+            }
+        }
+
+        self.free_cluster_index = start + count;
+        Ok(start)
+    }
+
+    /// Allocates `count` contiguous host clusters with a refcount of 1 and
+    /// returns the host offset of the first one. The content of the clusters
+    /// is not initialized.
+    pub(super) fn alloc_clusters(&mut self, count: u64) -> io::Result<u64> {
+        let first = self.find_free_clusters(count)?;
+        for cluster in first..first + count {
+            self.set_refcount(cluster, 1)?;
+        }
+
+        let host_offset = first * self.cluster_size() as u64;
+        debug!("Allocated {} cluster(s) at 0x{:016x}", count, host_offset);
+        Ok(host_offset)
+    }
+
+    fn alloc_refcount_block(&mut self, table_index: u64) -> io::Result<u64> {
+        if table_index >= self.refcount_table_entries() {
+            self.grow_refcount_table(table_index + 1)?;
+            // Accounting the new table may have already allocated our block
+            if let Some(off) = self.refcount_block_offset(table_index)? {
+                return Ok(off);
+            }
+        }
+
+        let cluster_sz = self.cluster_size() as u64;
+        let entries = self.refcount_block_entries();
+        let block_cluster = self.find_free_clusters(1)?;
+        let block_offset = block_cluster * cluster_sz;
+
+        // If the new block describes itself we set its refcount directly,
+        // otherwise it is done once the block is hooked into the table.
+        let mut block = vec![0u8; cluster_sz as usize];
+        let self_described = block_cluster / entries == table_index;
+        if self_described {
+            write_refcount(
+                &mut block,
+                block_cluster % entries,
+                self.refcount_order(),
+                1,
+            );
+        }
+
+        self.file.write_all_at(&block, block_offset)?;
+
+        let table_offset = self.refcount_table_offset();
+        self.file
+            .write_all_at(&block_offset.to_be_bytes(), table_offset + table_index * 8)?;
+
+        debug!(
+            "Allocated refcount block {} at 0x{:016x}",
+            table_index, block_offset
+        );
+
+        if !self_described {
+            self.set_refcount(block_cluster, 1)?;
+        }
+
+        Ok(block_offset)
+    }
+
+    // Moves the refcount table to a new location big enough to hold at least
+    // `min_entries` entries.
+    fn grow_refcount_table(&mut self, min_entries: u64) -> io::Result<()> {
+        let cluster_sz = self.cluster_size() as u64;
+        let entries_per_cluster = cluster_sz / 8;
+        let block_entries = self.refcount_block_entries();
+
+        // The new table must also be able to describe the clusters it lives in
+        // and a few refcount blocks after it.
+        let hint = self.free_cluster_index;
+        let mut new_clusters = min_entries.div_ceil(entries_per_cluster);
+        let new_start = loop {
+            self.free_cluster_index = hint;
+            let start = self.find_free_clusters(new_clusters)?;
+            let needed = min_entries.max((start + new_clusters) / block_entries + 2);
+            if needed <= new_clusters * entries_per_cluster {
+                break start;
+            }
+            new_clusters = needed.div_ceil(entries_per_cluster);
+        };
+
+        let old_offset = self.refcount_table_offset();
+        let old_clusters = self.refcount_table_clusters();
+
+        let mut table = vec![0u8; (new_clusters * cluster_sz) as usize];
+        self.file.read_exact_at(
+            &mut table[..(old_clusters * cluster_sz) as usize],
+            old_offset,
+        )?;
+
+        let new_offset = new_start * cluster_sz;
+        self.file.write_all_at(&table, new_offset)?;
+        self.file.sync_data()?;
+
+        self.header.refcount_table_offset = new_offset;
+        self.header.refcount_table_clusters = new_clusters as u32;
+        self.write_header()?;
+
+        debug!(
+            "Refcount table moved from 0x{:016x} ({} clusters) to 0x{:016x} ({} clusters)",
+            old_offset, old_clusters, new_offset, new_clusters
+        );
+
+        for cluster in new_start..new_start + new_clusters {
+            self.set_refcount(cluster, 1)?;
+        }
+
+        let old_start = old_offset / cluster_sz;
+        for cluster in old_start..old_start + old_clusters {
+            self.set_refcount(cluster, 0)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_refcount, write_refcount};
+
+    #[test]
+    fn sub_byte_refcounts_start_from_the_low_bits() {
+        let block = [0b1110_0100, 0b0000_0001];
+        // 1 bit
+        assert_eq!(read_refcount(&block, 0, 0), 0);
+        assert_eq!(read_refcount(&block, 2, 0), 1);
+        assert_eq!(read_refcount(&block, 8, 0), 1);
+        // 2 bits
+        assert_eq!(read_refcount(&block, 0, 1), 0);
+        assert_eq!(read_refcount(&block, 1, 1), 1);
+        assert_eq!(read_refcount(&block, 2, 1), 2);
+        assert_eq!(read_refcount(&block, 3, 1), 3);
+        // 4 bits
+        assert_eq!(read_refcount(&block, 0, 2), 0x4);
+        assert_eq!(read_refcount(&block, 1, 2), 0xe);
+        assert_eq!(read_refcount(&block, 2, 2), 0x1);
+    }
+
+    #[test]
+    fn wide_refcounts_are_big_endian() {
+        let block = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
+        assert_eq!(read_refcount(&block, 1, 3), 0x02);
+        assert_eq!(read_refcount(&block, 1, 4), 0x0304);
+        assert_eq!(read_refcount(&block, 1, 5), 0x0506_0708);
+        assert_eq!(read_refcount(&block, 0, 6), 0x0102_0304_0506_0708);
+    }
+
+    #[test]
+    fn write_leaves_the_neighbours_untouched() {
+        for order in 0..=6 {
+            let width = 1u32 << order;
+            let max = u64::MAX >> (64 - width);
+            let mut block = vec![0xffu8; 64];
+
+            write_refcount(&mut block, 3, order, 0);
+            assert_eq!(read_refcount(&block, 3, order), 0, "order {}", order);
+            for index in [2, 4] {
+                assert_eq!(read_refcount(&block, index, order), max, "order {}", order);
+            }
+
+            let value = max / 3;
+            write_refcount(&mut block, 3, order, value);
+            assert_eq!(read_refcount(&block, 3, order), value, "order {}", order);
+        }
+    }
+
+    #[test]
+    fn write_masks_values_too_wide() {
+        let mut block = [0u8; 1];
+        write_refcount(&mut block, 1, 1, 0b111);
+        assert_eq!(block[0], 0b0000_1100);
+    }
+}
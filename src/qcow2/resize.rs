@@ -0,0 +1,329 @@
+use log::debug;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use super::{L1Entry, L2Bitmap, L2Entry, Qcow2};
+
+impl Qcow2 {
+    /// Changes the virtual size of the image. Growing relocates the L1 table
+    /// when it doesn't fit in its clusters anymore and makes sure the new
+    /// area reads as zeros. Shrinking fails if clusters beyond the new end
+    /// are allocated unless `discard` is set, then they are freed.
+    pub fn resize(&mut self, size: u64, discard: bool) -> io::Result<()> {
+        self.check_writable()?;
+
+        if !size.is_multiple_of(512) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Size {} is not a multiple of 512", size),
+            ));
+        }
+
+        let old_size = self.virtual_size();
+        let cluster_sz = self.cluster_size() as u64;
+        let l1_size = size.div_ceil(cluster_sz * self.l2_entries());
+        if l1_size > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Size {} is too large for the cluster size", size),
+            ));
+        }
+
+        if size < old_size {
+            // Shared clusters would need copying to keep the snapshots
+            if self.nb_snapshots() > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Can't shrink an image with internal snapshots",
+                ));
+            }
+
+            let allocated = self.clusters_beyond(size)?;
+            if allocated > 0 && !discard {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} cluster(s) beyond the new size {} are allocated",
+                        allocated, size
+                    ),
+                ));
+            }
+
+            self.header.size = size;
+            self.write_header()?;
+            self.shrink_l1_table(size, l1_size)?;
+        } else if size > old_size {
+            if l1_size > self.l1_size() {
+                self.grow_l1_table(l1_size)?;
+            }
+
+            self.header.size = size;
+            self.write_header()?;
+            self.zero_grown_area(old_size, size)?;
+        }
+
+        self.flush()?;
+        debug!("Resized image from {} to {} bytes", old_size, size);
+        Ok(())
+    }
+
+    // Returns the number of guest clusters beyond `size` that don't read from
+    // the backing file.
+    fn clusters_beyond(&self, size: u64) -> io::Result<u64> {
+        let first = size.div_ceil(self.cluster_size() as u64);
+        let l2_entries = self.l2_entries();
+        let mut allocated = 0;
+
+        for (l1_index, l1_entry) in self
+            .read_l1_table(self.l1_table_offset(), self.l1_size())?
+            .into_iter()
+            .enumerate()
+        {
+            let l2_offset = match l1_entry {
+                L1Entry::Unallocated => continue,
+                L1Entry::L2Table { offset, .. } => offset,
+            };
+
+            let start = l1_index as u64 * l2_entries;
+            for (l2_index, (entry, bitmap)) in
+                self.read_l2_table(l2_offset)?.into_iter().enumerate()
+            {
+                if start + (l2_index as u64) < first {
+                    continue;
+                }
+                if entry != L2Entry::Unallocated || bitmap.is_some_and(|b| b.0 != 0) {
+                    allocated += 1;
+                }
+            }
+        }
+
+        Ok(allocated)
+    }
+
+    // Frees the clusters mapped beyond `size` and the L2 tables that are
+    // entirely beyond it, then reduces the L1 table to `l1_size` entries.
+    fn shrink_l1_table(&mut self, size: u64, l1_size: u64) -> io::Result<()> {
+        let cluster_sz = self.cluster_size() as u64;
+        let first = size.div_ceil(cluster_sz);
+        let cluster_bits = self.cluster_bits();
+        let l2_entries = self.l2_entries();
+        let entry_size = self.l2_entry_size();
+        let l1_offset = self.header.l1_table_offset;
+        let old_l1_size = self.header.l1_size as u64;
+        let unallocated_bitmap = self.is_extended_l2().then_some(L2Bitmap(0));
+
+        let l1_table = self.read_l1_table(l1_offset, old_l1_size)?;
+        for (l1_index, l1_entry) in l1_table.into_iter().enumerate() {
+            let l1_index = l1_index as u64;
+            let l2_offset = match l1_entry {
+                L1Entry::Unallocated => continue,
+                L1Entry::L2Table { offset, .. } => offset,
+            };
+
+            let start = l1_index * l2_entries;
+            if start + l2_entries <= first {
+                continue;
+            }
+
+            for (l2_index, (entry, _)) in self.read_l2_table(l2_offset)?.into_iter().enumerate() {
+                let l2_index = l2_index as u64;
+                if start + l2_index < first {
+                    continue;
+                }
+
+                // The entry is cleared before its clusters are freed so a
+                // failure only leaks them.
+                self.write_l2_entry(
+                    l2_offset + l2_index * entry_size,
+                    L2Entry::Unallocated,
+                    unallocated_bitmap,
+                )?;
+                if let Some((first_host, last_host)) = entry.host_clusters(cluster_bits) {
+                    for cluster in first_host..=last_host {
+                        self.decrement_refcount(cluster)?;
+                    }
+                }
+            }
+
+            if l1_index >= l1_size {
+                self.write_entry(l1_offset + l1_index * 8, 0)?;
+                self.decrement_refcount(l2_offset >> cluster_bits)?;
+            }
+        }
+
+        self.header.l1_size = l1_size as u32;
+        self.write_header()?;
+
+        // The clusters of the table that are not needed anymore are freed
+        let used = (l1_size * 8).next_multiple_of(cluster_sz);
+        let allocated = (old_l1_size * 8).next_multiple_of(cluster_sz);
+        self.free_clusters(l1_offset + used, allocated.saturating_sub(used))
+    }
+
+    // Makes room for `l1_size` entries in the L1 table, moving it to new
+    // clusters when its current ones are too small.
+    fn grow_l1_table(&mut self, l1_size: u64) -> io::Result<()> {
+        let cluster_sz = self.cluster_size() as u64;
+        let old_offset = self.header.l1_table_offset;
+        let old_size = self.header.l1_size as u64;
+        let old_len = old_size * 8;
+
+        // The new entries are cleared in case the clusters hold leftovers
+        if old_len.div_ceil(cluster_sz) * cluster_sz >= l1_size * 8 && old_offset != 0 {
+            let zeros = vec![0u8; ((l1_size - old_size) * 8) as usize];
+            self.file.write_all_at(&zeros, old_offset + old_len)?;
+            self.file.sync_data()?;
+
+            self.header.l1_size = l1_size as u32;
+            return self.write_header();
+        }
+
+        let mut table = vec![0u8; (l1_size * 8) as usize];
+        self.file
+            .read_exact_at(&mut table[..old_len as usize], old_offset)?;
+        let offset = self.write_new_clusters(&table)?;
+        self.file.sync_data()?;
+
+        self.header.l1_table_offset = offset;
+        self.header.l1_size = l1_size as u32;
+        self.write_header()?;
+
+        debug!(
+            "L1 table moved from 0x{:016x} to 0x{:016x} ({} entries)",
+            old_offset, offset, l1_size
+        );
+
+        self.free_clusters(old_offset, old_len)
+    }
+
+    // The area between the old and the new size must read as zeros but it
+    // can hold leftovers of the last cluster or show data of a backing file
+    // larger than the image.
+    fn zero_grown_area(&mut self, old_size: u64, size: u64) -> io::Result<()> {
+        let cluster_sz = self.cluster_size() as u64;
+        let backing_size = match &self.backing {
+            Some(backing) => backing.size()?,
+            None => 0,
+        };
+        let end = size.min(old_size.next_multiple_of(cluster_sz).max(backing_size));
+
+        let mut offset = old_size;
+        while offset < end {
+            let in_cluster = offset % cluster_sz;
+            let len = (end - offset).min(cluster_sz - in_cluster);
+
+            let data = self.read_guest_cluster(offset / cluster_sz)?;
+            let start = in_cluster as usize;
+            let stop = (start + len as usize).min(data.len());
+            if data.len() > start && data[start..stop].iter().any(|&b| b != 0) {
+                self.write_at(offset, &vec![0u8; len as usize])?;
+            }
+
+            offset += len;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CreateOptions, L1Entry, Qcow2};
+    use crate::testutil::TempFile;
+
+    fn create(path: &str, virtual_size: u64, cluster_bits: u32) -> Qcow2 {
+        let opts = CreateOptions {
+            virtual_size,
+            cluster_bits,
+            ..Default::default()
+        };
+        Qcow2::create(path, opts).unwrap()
+    }
+
+    fn assert_clean(q: &Qcow2) {
+        let report = q.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn grow_moves_the_l1_table() {
+        let tmp = TempFile::new("resize-grow");
+        // An L2 table of 4 KiB clusters maps 2 MiB and an L1 cluster 1 GiB
+        let mut q = create(tmp.path(), 2 << 20, 12);
+        q.write_at(0, &[1; 8192]).unwrap();
+        let l1_offset = q.l1_table_offset();
+
+        q.resize(3 << 30, false).unwrap();
+        assert_eq!(q.virtual_size(), 3 << 30);
+        assert_eq!(q.l1_size(), 1536);
+        assert_ne!(q.l1_table_offset(), l1_offset);
+        assert_clean(&q);
+
+        // Writes across the boundary of L1 clusters
+        let offset = (1 << 30) - 4096;
+        q.write_at(offset, &[2; 8192]).unwrap();
+        assert!(matches!(q.l1_entry(511).unwrap(), L1Entry::L2Table { .. }));
+        assert!(matches!(q.l1_entry(512).unwrap(), L1Entry::L2Table { .. }));
+        drop(q);
+
+        let q = Qcow2::new(tmp.path()).unwrap();
+        assert_eq!(q.read_at(0, 8192).unwrap(), vec![1; 8192]);
+        assert_eq!(q.read_at(offset, 8192).unwrap(), vec![2; 8192]);
+        assert_eq!(q.read_at((3 << 30) - 512, 512).unwrap(), vec![0; 512]);
+        assert_clean(&q);
+    }
+
+    #[test]
+    fn shrink_is_refused_with_snapshots() {
+        let tmp = TempFile::new("resize-snapshot");
+        let mut q = create(tmp.path(), 1 << 20, 16);
+        q.create_snapshot("snap").unwrap();
+
+        let e = q.resize(512 << 10, true).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::Unsupported);
+        assert_eq!(q.virtual_size(), 1 << 20);
+
+        // Growing is still allowed
+        q.resize(2 << 20, false).unwrap();
+        assert_clean(&q);
+    }
+
+    #[test]
+    fn shrink_is_refused_over_allocated_clusters() {
+        let tmp = TempFile::new("resize-allocated");
+        let mut q = create(tmp.path(), 1 << 20, 16);
+        q.write_at(800 << 10, &[1; 4096]).unwrap();
+
+        let e = q.resize(512 << 10, false).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(q.virtual_size(), 1 << 20);
+        assert_eq!(q.read_at(800 << 10, 4096).unwrap(), vec![1; 4096]);
+
+        // Only unallocated clusters are cut off
+        q.resize(896 << 10, false).unwrap();
+        assert_eq!(q.virtual_size(), 896 << 10);
+
+        let host_offset = q.l2_entry(12).unwrap().host_offset().unwrap();
+        q.resize(512 << 10, true).unwrap();
+        assert_eq!(q.get_refcount(host_offset >> 16).unwrap(), 0);
+        assert_clean(&q);
+    }
+
+    #[test]
+    fn regrown_area_reads_as_zeros() {
+        let tmp = TempFile::new("resize-regrow");
+        let mut q = create(tmp.path(), 1 << 20, 16);
+        q.write_at(0, &[1; 1 << 20]).unwrap();
+
+        // The size falls in the middle of cluster 4 that keeps its data
+        let size = 300_032;
+        q.resize(size, true).unwrap();
+        q.resize((1 << 20) - 512, false).unwrap();
+
+        let data = q.read_at(0, 1 << 20).unwrap();
+        assert_eq!(data.len(), (1 << 20) - 512);
+        assert!(data[..size as usize].iter().all(|&b| b == 1));
+        assert!(data[size as usize..].iter().all(|&b| b == 0));
+        assert_clean(&q);
+    }
+}
@@ -0,0 +1,539 @@
+use log::debug;
+use serde::Serialize;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::header::{be_u16, be_u32, be_u64};
+use super::{L1Entry, QCOW_OFLAG_COPIED, Qcow2};
+
+// https://github.com/qemu/qemu/blob/master/docs/interop/qcow2.txt
+//
+// Offset of the fields of a snapshot table entry:
+//   L1TableOffset = 0,          // 8 bytes
+//   L1Size = 8,                 // 4
+//   IdStrSize = 12,             // 2
+//   NameSize = 14,              // 2
+//   DateSec = 16,               // 4
+//   DateNsec = 20,              // 4
+//   VmClockNsec = 24,           // 8
+//   VmStateSize = 32,           // 4
+//   ExtraDataSize = 36,         // 4
+//   ExtraData = 40,             // variable
+//   // Followed by the id and the name, the entry is padded to 8 bytes.
+//
+// Offset of the fields of the extra data, each one is optional:
+//   VmStateSizeLarge = 0,       // 8
+//   DiskSize = 8,               // 8
+//   Icount = 16,                // 8
+
+// Length of the fixed part of an entry
+const SNAPSHOT_HEADER_SIZE: usize = 40;
+
+// Qemu refuses images with more snapshots
+const MAX_SNAPSHOTS: u64 = 65536;
+
+/// An internal snapshot as stored in the snapshot table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Snapshot {
+    pub id: String,
+    pub name: String,
+    pub l1_table_offset: u64,
+    pub l1_size: u32,
+    /// Size of the saved VM state, 0 if there is none
+    pub vm_state_size: u64,
+    /// Time when the snapshot was taken, since the epoch
+    pub date_sec: u32,
+    pub date_nsec: u32,
+    /// Guest clock when the snapshot was taken
+    pub vm_clock_nsec: u64,
+    /// Virtual size of the image when the snapshot was taken
+    pub disk_size: Option<u64>,
+    /// Instruction count of the VM, only if record/replay was used
+    pub icount: Option<u64>,
+    /// Extra data as stored on disk, including the fields above
+    pub extra_data: Vec<u8>,
+}
+
+// Returns the optional field of the extra data at `offset` if it is present
+fn extra_u64(extra_data: &[u8], offset: usize) -> Option<u64> {
+    (extra_data.len() >= offset + 8).then(|| be_u64(extra_data, offset))
+}
+
+fn invalid(index: u64, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid snapshot table entry {}: {}", index, reason),
+    )
+}
+
+impl Snapshot {
+    /// Reads the `nb_snapshots` entries of the table at `offset`.
+    pub fn read_table(file: &File, offset: u64, nb_snapshots: u64) -> io::Result<Vec<Self>> {
+        if nb_snapshots > MAX_SNAPSHOTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Too many snapshots {}", nb_snapshots),
+            ));
+        }
+
+        let mut snapshots = Vec::with_capacity(nb_snapshots as usize);
+        let mut offset = offset;
+
+        for i in 0..nb_snapshots {
+            let mut header = [0u8; SNAPSHOT_HEADER_SIZE];
+            file.read_exact_at(&mut header, offset)?;
+
+            let id_len = be_u16(&header, 12) as usize;
+            let name_len = be_u16(&header, 14) as usize;
+            let extra_len = be_u32(&header, 36) as usize;
+
+            let mut buf = vec![0u8; extra_len + id_len + name_len];
+            file.read_exact_at(&mut buf, offset + SNAPSHOT_HEADER_SIZE as u64)?;
+
+            let name = buf.split_off(extra_len + id_len);
+            let id = buf.split_off(extra_len);
+            let extra_data = buf;
+
+            let id = String::from_utf8(id).map_err(|_| invalid(i, "id is not valid UTF-8"))?;
+            let name =
+                String::from_utf8(name).map_err(|_| invalid(i, "name is not valid UTF-8"))?;
+
+            let snapshot = Snapshot {
+                id,
+                name,
+                l1_table_offset: be_u64(&header, 0),
+                l1_size: be_u32(&header, 8),
+                vm_state_size: extra_u64(&extra_data, 0).unwrap_or(be_u32(&header, 32) as u64),
+                date_sec: be_u32(&header, 16),
+                date_nsec: be_u32(&header, 20),
+                vm_clock_nsec: be_u64(&header, 24),
+                disk_size: extra_u64(&extra_data, 8),
+                icount: extra_u64(&extra_data, 16).filter(|&icount| icount != u64::MAX),
+                extra_data,
+            };
+
+            offset += snapshot.size();
+            snapshots.push(snapshot);
+        }
+
+        Ok(snapshots)
+    }
+
+    /// Returns the size of the entry in the snapshot table.
+    pub fn size(&self) -> u64 {
+        let len = SNAPSHOT_HEADER_SIZE + self.extra_data.len() + self.id.len() + self.name.len();
+        len.div_ceil(8) as u64 * 8
+    }
+
+    /// Returns the entry as it is stored in the snapshot table.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.size() as usize);
+
+        buf.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        buf.extend_from_slice(&self.l1_size.to_be_bytes());
+        buf.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.date_sec.to_be_bytes());
+        buf.extend_from_slice(&self.date_nsec.to_be_bytes());
+        buf.extend_from_slice(&self.vm_clock_nsec.to_be_bytes());
+        // The whole size is in the extra data when it doesn't fit
+        buf.extend_from_slice(&(self.vm_state_size as u32).to_be_bytes());
+        buf.extend_from_slice(&(self.extra_data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&self.extra_data);
+        buf.extend_from_slice(self.id.as_bytes());
+        buf.extend_from_slice(self.name.as_bytes());
+        buf.resize(self.size() as usize, 0);
+
+        buf
+    }
+}
+
+impl Qcow2 {
+    /// Returns the internal snapshots of the image.
+    pub fn snapshots(&self) -> io::Result<Vec<Snapshot>> {
+        Snapshot::read_table(&self.file, self.snapshots_offset(), self.nb_snapshots())
+    }
+
+    /// Looks for a snapshot by id first and then by name, like qemu-img.
+    pub fn find_snapshot(&self, id_or_name: &str) -> io::Result<Snapshot> {
+        let snapshots = self.snapshots()?;
+        let found = match snapshots.iter().position(|s| s.id == id_or_name) {
+            Some(index) => Some(index),
+            None => snapshots.iter().position(|s| s.name == id_or_name),
+        };
+
+        match found {
+            Some(index) => Ok(snapshots[index].clone()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Snapshot {} not found", id_or_name),
+            )),
+        }
+    }
+
+    /// Opens the image read-only as it was when the snapshot was taken. Reads
+    /// go through the L1 table of the snapshot and the virtual size is the
+    /// one of the snapshot.
+    pub fn open_snapshot(fname: &str, id_or_name: &str) -> io::Result<Self> {
+        let mut q = Qcow2::open(fname, false)?;
+        let snapshot = q.find_snapshot(id_or_name)?;
+        debug!(
+            "Viewing snapshot {} ({}) with L1 table at 0x{:08x}",
+            snapshot.id, snapshot.name, snapshot.l1_table_offset
+        );
+        q.snapshot = Some(snapshot);
+        Ok(q)
+    }
+
+    /// Returns the snapshot the image is a view of, if any.
+    pub fn viewed_snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    /// Creates an internal snapshot of the current state of the image. The
+    /// snapshot shares all its clusters with the image until they are
+    /// written.
+    pub fn create_snapshot(&mut self, name: &str) -> io::Result<Snapshot> {
+        self.check_writable()?;
+
+        if self.max_refcount() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Snapshots need refcounts of at least 2 bits",
+            ));
+        }
+
+        let mut snapshots = self.snapshots()?;
+        if snapshots.iter().any(|s| s.name == name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Snapshot {} already exists", name),
+            ));
+        }
+
+        // Ids are numbers, the new one follows the highest one
+        let id = snapshots
+            .iter()
+            .filter_map(|s| s.id.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+
+        // Everything reachable from the active L1 table gets a reference from
+        // the snapshot. As nothing is owned by the image alone anymore the
+        // COPIED flags are cleared.
+        let l1_offset = self.l1_table_offset();
+        let l1_size = self.l1_size();
+        self.update_l1_refcounts(l1_offset, l1_size, true)?;
+        self.update_copied_flags()?;
+
+        let mut table = vec![0u8; l1_size as usize * 8];
+        self.file.read_exact_at(&mut table, l1_offset)?;
+        for chunk in table.chunks_exact_mut(8) {
+            let entry = u64::from_be_bytes((&*chunk).try_into().unwrap());
+            chunk.copy_from_slice(&(entry & !QCOW_OFLAG_COPIED).to_be_bytes());
+        }
+        let snapshot_l1_offset = self.write_new_clusters(&table)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut extra_data = Vec::with_capacity(16);
+        extra_data.extend_from_slice(&0u64.to_be_bytes());
+        extra_data.extend_from_slice(&self.virtual_size().to_be_bytes());
+
+        let snapshot = Snapshot {
+            id: id.to_string(),
+            name: name.to_string(),
+            l1_table_offset: snapshot_l1_offset,
+            l1_size: l1_size as u32,
+            vm_state_size: 0,
+            date_sec: now.as_secs() as u32,
+            date_nsec: now.subsec_nanos(),
+            vm_clock_nsec: 0,
+            disk_size: Some(self.virtual_size()),
+            icount: None,
+            extra_data,
+        };
+
+        snapshots.push(snapshot.clone());
+        self.write_snapshot_table(&snapshots)?;
+        self.flush()?;
+
+        debug!("Created snapshot {} ({})", snapshot.id, snapshot.name);
+        Ok(snapshot)
+    }
+
+    /// Reverts the image to the state it had when the snapshot was taken.
+    /// The snapshot is kept.
+    pub fn apply_snapshot(&mut self, id_or_name: &str) -> io::Result<()> {
+        self.check_writable()?;
+        let snapshot = self.find_snapshot(id_or_name)?;
+
+        // The active L1 table becomes a copy of the one of the snapshot
+        let l1_size = snapshot.l1_size as u64;
+        self.update_l1_refcounts(snapshot.l1_table_offset, l1_size, true)?;
+
+        let mut table = vec![0u8; l1_size as usize * 8];
+        self.file
+            .read_exact_at(&mut table, snapshot.l1_table_offset)?;
+        let l1_offset = self.write_new_clusters(&table)?;
+
+        let old_l1_offset = self.header.l1_table_offset;
+        let old_l1_size = self.header.l1_size as u64;
+
+        self.header.l1_table_offset = l1_offset;
+        self.header.l1_size = snapshot.l1_size;
+        self.header.size = snapshot.disk_size.unwrap_or(self.header.size);
+        self.write_header()?;
+
+        // Drop what was only referenced by the previous state
+        self.update_l1_refcounts(old_l1_offset, old_l1_size, false)?;
+        self.free_clusters(old_l1_offset, old_l1_size * 8)?;
+        self.update_copied_flags()?;
+        self.flush()?;
+
+        debug!("Applied snapshot {} ({})", snapshot.id, snapshot.name);
+        Ok(())
+    }
+
+    /// Deletes the snapshot and frees the clusters only it was using.
+    pub fn delete_snapshot(&mut self, id_or_name: &str) -> io::Result<()> {
+        self.check_writable()?;
+        let snapshot = self.find_snapshot(id_or_name)?;
+
+        // The snapshot is removed from the table first so a failure after
+        // that only leaks clusters.
+        let mut snapshots = self.snapshots()?;
+        snapshots.retain(|s| s.id != snapshot.id);
+        self.write_snapshot_table(&snapshots)?;
+
+        let l1_size = snapshot.l1_size as u64;
+        self.update_l1_refcounts(snapshot.l1_table_offset, l1_size, false)?;
+        self.free_clusters(snapshot.l1_table_offset, l1_size * 8)?;
+
+        // Clusters shared with the snapshot can now be owned by the image
+        self.update_copied_flags()?;
+        self.flush()?;
+
+        debug!("Deleted snapshot {} ({})", snapshot.id, snapshot.name);
+        Ok(())
+    }
+
+    // Adds or drops one reference to every L2 table and data cluster that is
+    // reachable from the L1 table. The tables are all read before any
+    // refcount is updated so an L2 table can be freed on the way.
+    pub(super) fn update_l1_refcounts(
+        &mut self,
+        l1_offset: u64,
+        l1_size: u64,
+        increment: bool,
+    ) -> io::Result<()> {
+        let cluster_bits = self.cluster_bits();
+
+        let mut clusters = Vec::new();
+        for l1_entry in self.read_l1_table(l1_offset, l1_size)? {
+            let l2_offset = match l1_entry {
+                L1Entry::Unallocated => continue,
+                L1Entry::L2Table { offset, .. } => offset,
+            };
+
+            for (l2_entry, _) in self.read_l2_table(l2_offset)? {
+                if let Some((first, last)) = l2_entry.host_clusters(cluster_bits) {
+                    clusters.extend(first..=last);
+                }
+            }
+            clusters.push(l2_offset >> cluster_bits);
+        }
+
+        if increment {
+            self.check_refcount_headroom(&mut clusters)?;
+        }
+
+        for cluster in clusters {
+            if increment {
+                self.increment_refcount(cluster)?;
+            } else {
+                self.decrement_refcount(cluster)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Fails if a refcount would overflow once every cluster of `clusters`
+    // gets one more reference, a cluster can be listed several times (e.g.
+    // when it holds compressed data of several guest clusters). Nothing is
+    // written so the image is left untouched on failure.
+    fn check_refcount_headroom(&self, clusters: &mut [u64]) -> io::Result<()> {
+        let max_refcount = self.max_refcount();
+
+        clusters.sort_unstable();
+        for run in clusters.chunk_by(|a, b| a == b) {
+            let refcount = self.get_refcount(run[0])?;
+            if refcount.saturating_add(run.len() as u64) > max_refcount {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Refcount of cluster {} would exceed the maximum of {}",
+                        run[0], max_refcount
+                    ),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes `data` to newly allocated clusters and returns their offset, or
+    // 0 if there is nothing to write.
+    pub(super) fn write_new_clusters(&mut self, data: &[u8]) -> io::Result<u64> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let offset =
+            self.alloc_clusters((data.len() as u64).div_ceil(self.cluster_size() as u64))?;
+        self.file.write_all_at(data, offset)?;
+        Ok(offset)
+    }
+
+    // Writes the snapshot table to new clusters, points the header to it and
+    // frees the previous one.
+    fn write_snapshot_table(&mut self, snapshots: &[Snapshot]) -> io::Result<()> {
+        let old_offset = self.snapshots_offset();
+        let old_len: u64 = self.snapshots()?.iter().map(|s| s.size()).sum();
+
+        let table: Vec<u8> = snapshots.iter().flat_map(|s| s.to_bytes()).collect();
+        let offset = self.write_new_clusters(&table)?;
+        self.file.sync_data()?;
+
+        self.header.nb_snapshots = snapshots.len() as u32;
+        self.header.snapshots_offset = offset;
+        self.write_header()?;
+
+        self.free_clusters(old_offset, old_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::FileExt;
+
+    use super::super::{CreateOptions, Qcow2};
+    use super::Snapshot;
+    use crate::testutil::TempFile;
+
+    fn snapshot(id: &str, name: &str, extra: &[u64]) -> Snapshot {
+        let extra_data: Vec<u8> = extra.iter().flat_map(|v| v.to_be_bytes()).collect();
+        Snapshot {
+            id: id.to_string(),
+            name: name.to_string(),
+            l1_table_offset: 0x50000,
+            l1_size: 3,
+            vm_state_size: extra.first().copied().unwrap_or(0),
+            date_sec: 1_700_000_000,
+            date_nsec: 123,
+            vm_clock_nsec: 456,
+            disk_size: extra.get(1).copied(),
+            icount: extra.get(2).copied().filter(|&icount| icount != u64::MAX),
+            extra_data,
+        }
+    }
+
+    #[test]
+    fn snapshot_table_round_trip() {
+        let snapshots = vec![
+            // Version 2 entries have no extra data
+            snapshot("1", "a", &[]),
+            snapshot("2", "large vm state", &[5 << 32, 1 << 30]),
+            // No icount is stored as all ones
+            snapshot("10", "odd", &[0, 1 << 30, u64::MAX]),
+            // Unknown extra data is kept
+            snapshot("11", "future", &[0, 1 << 30, 7, 0xdead]),
+        ];
+        let table: Vec<u8> = snapshots.iter().flat_map(|s| s.to_bytes()).collect();
+        assert!(table.len().is_multiple_of(8));
+        assert_eq!(
+            snapshots.iter().map(Snapshot::size).sum::<u64>(),
+            table.len() as u64
+        );
+
+        let tmp = TempFile::new("snapshot-table");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(tmp.path())
+            .unwrap();
+        file.write_all_at(&table, 4096).unwrap();
+
+        let read = Snapshot::read_table(&file, 4096, snapshots.len() as u64).unwrap();
+        assert_eq!(read, snapshots);
+        let bytes: Vec<u8> = read.iter().flat_map(|s| s.to_bytes()).collect();
+        assert_eq!(bytes, table);
+
+        assert!(Snapshot::read_table(&file, 4096, 5).is_err());
+    }
+
+    #[test]
+    fn snapshot_fails_cleanly_when_refcounts_are_full() {
+        let tmp = TempFile::new("snapshot-headroom");
+        // 2 bits refcounts allow 2 snapshots of a cluster
+        let opts = CreateOptions {
+            virtual_size: 1 << 24,
+            refcount_order: 1,
+            ..Default::default()
+        };
+        let mut q = Qcow2::create(tmp.path(), opts).unwrap();
+        q.write_at(0, &[7; 1 << 20]).unwrap();
+
+        q.create_snapshot("a").unwrap();
+        q.create_snapshot("b").unwrap();
+        // The first cluster and the L2 table are copied and have room for a
+        // reference, the next clusters don't.
+        q.write_at(0, &[8; 512]).unwrap();
+        assert!(q.create_snapshot("c").is_err());
+
+        let report = q.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+        assert_eq!(q.snapshots().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn snapshot_reads_data_overwritten_in_the_image() {
+        let tmp = TempFile::new("snapshot-read");
+        let opts = CreateOptions {
+            virtual_size: 4 << 20,
+            ..Default::default()
+        };
+        let mut q = Qcow2::create(tmp.path(), opts).unwrap();
+        let before: Vec<u8> = (0..2 << 20).map(|i| (i >> 12) as u8).collect();
+        q.write_at(0, &before).unwrap();
+        q.create_snapshot("before").unwrap();
+
+        // Overwrite, discard and grow the active image
+        q.write_at(1000, &[0xee; 1 << 20]).unwrap();
+        q.discard(1 << 20, 1 << 20).unwrap();
+        q.resize(8 << 20, false).unwrap();
+        q.write_at(6 << 20, &[0xdd; 4096]).unwrap();
+        drop(q);
+
+        for id_or_name in ["before", "1"] {
+            let snapshot = Qcow2::open_snapshot(tmp.path(), id_or_name).unwrap();
+            assert_eq!(snapshot.virtual_size(), 4 << 20);
+            assert!(snapshot.read_at(0, 2 << 20).unwrap() == before);
+            assert!(snapshot.read_at(2 << 20, 8 << 20).unwrap() == vec![0; 2 << 20]);
+        }
+
+        let q = Qcow2::new(tmp.path()).unwrap();
+        assert!(q.read_at(1000, (1 << 20) - 1000).unwrap() == vec![0xee; (1 << 20) - 1000]);
+        assert!(q.read_at(1 << 20, 1 << 20).unwrap() == vec![0; 1 << 20]);
+        assert!(q.read_at(6 << 20, 4096).unwrap() == vec![0xdd; 4096]);
+    }
+}
@@ -0,0 +1,184 @@
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use super::{L2Bitmap, L2Entry, Qcow2, Subcluster};
+
+// With extended L2 entries each of the 32 subclusters of a guest cluster can
+// be allocated, read as zeros or come from the backing file. Clusters are
+// always allocated whole on write, so subclusters only matter when reading
+// and when writing to a cluster whose bitmap is not full.
+impl Qcow2 {
+    // Fills `data` with guest cluster N when each of its subclusters can come
+    // from a different place.
+    pub(super) fn read_subclusters(
+        &self,
+        n: u64,
+        host_offset: Option<u64>,
+        bitmap: L2Bitmap,
+        data: &mut [u8],
+    ) -> io::Result<()> {
+        let subcluster_sz = data.len() / L2Bitmap::SUBCLUSTERS as usize;
+        let mut backing = None;
+
+        for (i, chunk) in data.chunks_exact_mut(subcluster_sz).enumerate() {
+            let offset = (i * subcluster_sz) as u64;
+            match bitmap.subcluster(i as u64) {
+                Subcluster::Zero => chunk.fill(0),
+                Subcluster::Unallocated => {
+                    // The backing cluster is read once for all subclusters
+                    if backing.is_none() {
+                        let mut cluster = vec![0u8; self.cluster_size()];
+                        self.read_backing_cluster(n, &mut cluster)?;
+                        backing = Some(cluster);
+                    }
+                    let cluster = backing.as_ref().unwrap();
+                    chunk.copy_from_slice(
+                        &cluster[offset as usize..offset as usize + subcluster_sz],
+                    );
+                }
+                Subcluster::Allocated => {
+                    // Parsing guarantees there is a host cluster, the data
+                    // can be truncated at the end of the file.
+                    chunk.fill(0);
+                    let host_offset = host_offset.unwrap_or_default();
+                    let mut done = 0;
+                    while done < chunk.len() {
+                        match self
+                            .data_file()
+                            .read_at(&mut chunk[done..], host_offset + offset + done as u64)?
+                        {
+                            0 => break,
+                            n => done += n,
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes `data` at `in_cluster` bytes in the host cluster of guest
+    // cluster N when some of its subclusters are not allocated. The whole
+    // cluster is written with the current guest data and all its subclusters
+    // become allocated.
+    pub(super) fn write_subclusters_in_place(
+        &mut self,
+        n: u64,
+        l2_entry_offset: u64,
+        host_offset: u64,
+        in_cluster: usize,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut cluster = self.read_guest_cluster(n)?;
+        cluster.resize(self.cluster_size(), 0);
+        cluster[in_cluster..in_cluster + data.len()].copy_from_slice(data);
+        self.data_file().write_all_at(&cluster, host_offset)?;
+
+        let entry = L2Entry::Normal {
+            host_offset,
+            copied: true,
+        };
+        self.write_l2_entry(l2_entry_offset, entry, Some(L2Bitmap::ALL_ALLOCATED))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CreateOptions, L2Bitmap, L2Entry, Qcow2};
+    use crate::testutil::TempFile;
+
+    const MIB: u64 = 1 << 20;
+    // 64 KiB clusters have subclusters of 2 KiB
+    const SUB: u64 = 2048;
+
+    // Returns an overlay with extended L2 entries over a base filled with 0xaa
+    fn overlay(base: &TempFile, top: &TempFile) -> Qcow2 {
+        let opts = CreateOptions {
+            virtual_size: MIB,
+            ..Default::default()
+        };
+        let mut q = Qcow2::create(base.path(), opts).unwrap();
+        q.write_at(0, &vec![0xaa; MIB as usize]).unwrap();
+
+        let opts = CreateOptions {
+            virtual_size: MIB,
+            backing_file: Some(base.path().to_string()),
+            backing_fmt: Some("qcow2".to_string()),
+            extended_l2: true,
+            ..Default::default()
+        };
+        Qcow2::create(top.path(), opts).unwrap()
+    }
+
+    #[test]
+    fn zeroed_subclusters_hide_the_backing_file() {
+        let (base, top) = (
+            TempFile::new("sub-zero-base"),
+            TempFile::new("sub-zero-top"),
+        );
+        let mut q = overlay(&base, &top);
+
+        q.write_zeroes(2 * SUB, 2 * SUB, false).unwrap();
+        assert_eq!(q.l2_entry(0).unwrap(), L2Entry::Unallocated);
+        assert_eq!(q.l2_bitmap(0).unwrap(), Some(L2Bitmap(0b1100 << 32)));
+
+        let data = q.read_guest_cluster(0).unwrap();
+        assert!(data[..2 * SUB as usize].iter().all(|&b| b == 0xaa));
+        assert!(
+            data[2 * SUB as usize..4 * SUB as usize]
+                .iter()
+                .all(|&b| b == 0)
+        );
+        assert!(data[4 * SUB as usize..].iter().all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn write_allocates_the_whole_cluster() {
+        let (base, top) = (
+            TempFile::new("sub-alloc-base"),
+            TempFile::new("sub-alloc-top"),
+        );
+        let mut q = overlay(&base, &top);
+
+        q.write_zeroes(2 * SUB, SUB, false).unwrap();
+        q.write_at(10 * SUB, b"hello").unwrap();
+        assert!(matches!(q.l2_entry(0).unwrap(), L2Entry::Normal { .. }));
+        assert_eq!(q.l2_bitmap(0).unwrap(), Some(L2Bitmap::ALL_ALLOCATED));
+
+        let data = q.read_guest_cluster(0).unwrap();
+        assert_eq!(data[0], 0xaa);
+        assert!(
+            data[2 * SUB as usize..3 * SUB as usize]
+                .iter()
+                .all(|&b| b == 0)
+        );
+        assert_eq!(&data[10 * SUB as usize..10 * SUB as usize + 5], b"hello");
+        assert!(q.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn write_in_place_fills_the_other_subclusters() {
+        let (base, top) = (
+            TempFile::new("sub-place-base"),
+            TempFile::new("sub-place-top"),
+        );
+        let mut q = overlay(&base, &top);
+
+        q.write_at(0, &[0x55; SUB as usize]).unwrap();
+        let host_offset = q.l2_entry(0).unwrap().host_offset();
+        q.write_zeroes(SUB, SUB, false).unwrap();
+        assert_ne!(q.l2_bitmap(0).unwrap(), Some(L2Bitmap::ALL_ALLOCATED));
+
+        q.write_at(5 * SUB, b"world").unwrap();
+        assert_eq!(q.l2_entry(0).unwrap().host_offset(), host_offset);
+        assert_eq!(q.l2_bitmap(0).unwrap(), Some(L2Bitmap::ALL_ALLOCATED));
+
+        let data = q.read_guest_cluster(0).unwrap();
+        assert!(data[..SUB as usize].iter().all(|&b| b == 0x55));
+        assert!(data[SUB as usize..2 * SUB as usize].iter().all(|&b| b == 0));
+        assert_eq!(data[2 * SUB as usize], 0xaa);
+        assert_eq!(&data[5 * SUB as usize..5 * SUB as usize + 5], b"world");
+        assert!(q.check().unwrap().is_clean());
+    }
+}
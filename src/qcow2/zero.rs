@@ -0,0 +1,241 @@
+use log::debug;
+use std::io;
+
+use super::{L2Bitmap, L2Entry, Qcow2};
+use crate::raw::is_zero;
+
+impl Qcow2 {
+    /// Makes the `len` bytes at `offset` read as zeros. Whole clusters are
+    /// marked with the zero flag instead of being written, with extended L2
+    /// entries so are whole subclusters. The host cluster is kept for future
+    /// writes unless `may_unmap` is set, then it is freed and punched out of
+    /// the file. The rest of the range is written with zeros if it doesn't
+    /// already read as zeros. Version 2 images have no zero flag so the
+    /// whole range is written.
+    pub fn write_zeroes(&mut self, offset: u64, len: u64, may_unmap: bool) -> io::Result<()> {
+        self.check_writable()?;
+        self.check_guest_range(offset, len)?;
+
+        let cluster_sz = self.cluster_size() as u64;
+        let virtual_size = self.virtual_size();
+        let end = offset + len;
+        let mut freed = Vec::new();
+        let mut pos = offset;
+
+        while pos < end {
+            let in_cluster = pos % cluster_sz;
+            let chunk = (end - pos).min(cluster_sz - in_cluster);
+            // The last cluster can be partially beyond the end of the image
+            let whole = in_cluster == 0 && (chunk == cluster_sz || pos + chunk == virtual_size);
+
+            if whole && self.version() >= 3 {
+                self.zero_cluster(pos / cluster_sz, may_unmap, &mut freed)?;
+            } else if self.is_extended_l2() {
+                self.zero_subclusters(pos, chunk)?;
+            } else {
+                self.write_zero_range(pos, chunk)?;
+            }
+
+            pos += chunk;
+        }
+
+        self.punch_clusters(freed);
+
+        debug!(
+            "Wrote zeroes on {} bytes at 0x{:016x}, may unmap: {}",
+            len, offset, may_unmap
+        );
+        Ok(())
+    }
+
+    // Sets the zero flag of guest cluster N, or all the zero bits of its
+    // bitmap with extended L2 entries.
+    fn zero_cluster(&mut self, n: u64, may_unmap: bool, freed: &mut Vec<u64>) -> io::Result<()> {
+        let (entry, bitmap) = self.l2_entry_and_bitmap(n)?;
+
+        // There is nothing to update when there is no data to hide
+        if entry == L2Entry::Unallocated && self.backing_file.is_none() {
+            return Ok(());
+        }
+
+        // Compressed clusters can't be kept as zero clusters
+        let (new_entry, new_bitmap) = match (entry, bitmap) {
+            (
+                L2Entry::Normal {
+                    host_offset,
+                    copied,
+                }
+                | L2Entry::ZeroPreallocated {
+                    host_offset,
+                    copied,
+                },
+                None,
+            ) if !may_unmap => (
+                L2Entry::ZeroPreallocated {
+                    host_offset,
+                    copied,
+                },
+                None,
+            ),
+            (L2Entry::Normal { .. }, Some(_)) if !may_unmap => (entry, Some(L2Bitmap::ALL_ZERO)),
+            (_, None) => (L2Entry::Zero, None),
+            (_, Some(_)) => (L2Entry::Unallocated, Some(L2Bitmap::ALL_ZERO)),
+        };
+
+        if (new_entry, new_bitmap) == (entry, bitmap) {
+            return Ok(());
+        }
+
+        self.set_l2_entry(n, new_entry, new_bitmap)?;
+        if new_entry.host_offset() != entry.host_offset() {
+            self.release_host_clusters(&entry, freed)?;
+        }
+        Ok(())
+    }
+
+    // Zeroes part of a guest cluster with extended L2 entries. The zero bits
+    // of the subclusters entirely in the range are set and the head and tail
+    // of the range are written.
+    fn zero_subclusters(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        let cluster_sz = self.cluster_size() as u64;
+        let subcluster_sz = cluster_sz / L2Bitmap::SUBCLUSTERS;
+        let n = offset / cluster_sz;
+        let cluster_start = n * cluster_sz;
+
+        let start = offset - cluster_start;
+        let first = start.div_ceil(subcluster_sz);
+        // The last subcluster can be partially beyond the end of the image
+        let last = match offset + len {
+            end if end == self.virtual_size() => (end - cluster_start).div_ceil(subcluster_sz),
+            end => (end - cluster_start) / subcluster_sz,
+        };
+
+        if first >= last {
+            return self.write_zero_range(offset, len);
+        }
+
+        let head_end = cluster_start + first * subcluster_sz;
+        let tail_start = (cluster_start + last * subcluster_sz).min(offset + len);
+        self.write_zero_range(offset, head_end - offset)?;
+        self.write_zero_range(tail_start, offset + len - tail_start)?;
+
+        let (entry, bitmap) = self.l2_entry_and_bitmap(n)?;
+        if entry == L2Entry::Unallocated && self.backing_file.is_none() {
+            return Ok(());
+        }
+        // Compressed clusters have no bitmap
+        if let L2Entry::Compressed { .. } = entry {
+            return self.write_zero_range(head_end, tail_start - head_end);
+        }
+
+        let bitmap = bitmap.unwrap_or(L2Bitmap(0));
+        let mask = (1u64 << last) - (1u64 << first);
+        let new_bitmap = L2Bitmap((bitmap.0 | (mask << 32)) & !mask);
+        if new_bitmap != bitmap {
+            self.set_l2_entry(n, entry, Some(new_bitmap))?;
+        }
+        Ok(())
+    }
+
+    // Writes zeros on the range unless it already reads as zeros
+    fn write_zero_range(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if len == 0 || is_zero(&self.read_at(offset, len as usize)?) {
+            return Ok(());
+        }
+        self.write_at(offset, &vec![0u8; len as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CreateOptions, L2Bitmap, L2Entry, Qcow2};
+    use crate::testutil::TempFile;
+
+    const CLUSTER_SIZE: u64 = 1 << 16;
+
+    // Creates an image whose first 3 clusters have data, with their host
+    // clusters.
+    fn create(tmp: &TempFile, extended_l2: bool) -> (Qcow2, Vec<u64>) {
+        let opts = CreateOptions {
+            virtual_size: 1 << 20,
+            extended_l2,
+            ..Default::default()
+        };
+        let mut q = Qcow2::create(tmp.path(), opts).unwrap();
+        q.write_at(0, &[1; 3 * CLUSTER_SIZE as usize]).unwrap();
+        let hosts = (0..3)
+            .map(|n| q.l2_entry(n).unwrap().host_offset().unwrap())
+            .collect();
+        (q, hosts)
+    }
+
+    fn assert_zeros(q: &Qcow2, offset: u64, len: u64) {
+        let data = q.read_at(offset, len as usize).unwrap();
+        assert!(data.iter().all(|&b| b == 0));
+        let report = q.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn whole_clusters_get_the_zero_flag() {
+        let tmp = TempFile::new("zero-flag");
+        let (mut q, hosts) = create(&tmp, false);
+        let cluster_bits = q.cluster_bits();
+
+        // The host cluster is kept for future writes
+        q.write_zeroes(0, CLUSTER_SIZE, false).unwrap();
+        assert_eq!(
+            q.l2_entry(0).unwrap(),
+            L2Entry::ZeroPreallocated {
+                host_offset: hosts[0],
+                copied: true
+            }
+        );
+        assert_eq!(q.get_refcount(hosts[0] >> cluster_bits).unwrap(), 1);
+
+        q.write_zeroes(CLUSTER_SIZE, CLUSTER_SIZE, true).unwrap();
+        assert_eq!(q.l2_entry(1).unwrap(), L2Entry::Zero);
+        assert_eq!(q.get_refcount(hosts[1] >> cluster_bits).unwrap(), 0);
+
+        // Unmapping a preallocated zero cluster frees it too
+        q.write_zeroes(0, CLUSTER_SIZE, true).unwrap();
+        assert_eq!(q.l2_entry(0).unwrap(), L2Entry::Zero);
+        assert_eq!(q.get_refcount(hosts[0] >> cluster_bits).unwrap(), 0);
+        assert_zeros(&q, 0, 2 * CLUSTER_SIZE);
+
+        // Unallocated clusters already read as zeros without backing file
+        q.write_zeroes(4 * CLUSTER_SIZE, CLUSTER_SIZE, true)
+            .unwrap();
+        assert_eq!(q.l2_entry(4).unwrap(), L2Entry::Unallocated);
+
+        // A partial cluster is written with zeros
+        q.write_zeroes(2 * CLUSTER_SIZE + 100, 1000, true).unwrap();
+        assert_eq!(
+            q.l2_entry(2).unwrap(),
+            L2Entry::Normal {
+                host_offset: hosts[2],
+                copied: true
+            }
+        );
+        assert_zeros(&q, 2 * CLUSTER_SIZE + 100, 1000);
+        assert_eq!(q.read_at(2 * CLUSTER_SIZE, 100).unwrap(), vec![1; 100]);
+    }
+
+    #[test]
+    fn whole_clusters_get_zero_bitmaps_with_extended_l2() {
+        let tmp = TempFile::new("zero-bitmap");
+        let (mut q, hosts) = create(&tmp, true);
+        let cluster_bits = q.cluster_bits();
+
+        q.write_zeroes(0, CLUSTER_SIZE, false).unwrap();
+        assert!(matches!(q.l2_entry(0).unwrap(), L2Entry::Normal { .. }));
+        assert_eq!(q.l2_bitmap(0).unwrap(), Some(L2Bitmap::ALL_ZERO));
+        assert_eq!(q.get_refcount(hosts[0] >> cluster_bits).unwrap(), 1);
+
+        q.write_zeroes(CLUSTER_SIZE, CLUSTER_SIZE, true).unwrap();
+        assert_eq!(q.l2_entry(1).unwrap(), L2Entry::Unallocated);
+        assert_eq!(q.l2_bitmap(1).unwrap(), Some(L2Bitmap::ALL_ZERO));
+        assert_eq!(q.get_refcount(hosts[1] >> cluster_bits).unwrap(), 0);
+        assert_zeros(&q, 0, 2 * CLUSTER_SIZE);
+    }
+}
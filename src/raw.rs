@@ -0,0 +1,215 @@
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+
+// Amount of data read from each device at once when comparing
+const DIFF_BUFFER_SIZE: u64 = 1 << 20;
+
+/// Returns the size of a raw file or block device. The length of a block
+/// device is not in its metadata, seeking to its end works for both.
+pub fn device_size(file: &File) -> io::Result<u64> {
+    let mut file = file;
+    file.seek(SeekFrom::End(0))
+}
+
+pub fn is_zero(buf: &[u8]) -> bool {
+    buf.iter().all(|&b| b == 0)
+}
+
+/// Fills `buf` with the data at `offset`, bytes beyond the end of the file
+/// are read as zeros.
+pub fn read_padded(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    let mut done = 0;
+    while done < buf.len() {
+        match file.read_at(&mut buf[done..], offset + done as u64)? {
+            0 => break,
+            n => done += n,
+        }
+    }
+    buf[done..].fill(0);
+    Ok(())
+}
+
+/// Deallocates the `len` bytes at `offset` of a file or block device, they
+/// read as zeros afterwards and the size is kept. Fails with
+/// [`io::ErrorKind::Unsupported`] when the file system can't do it.
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    // The descriptor stays open as long as `file` is borrowed
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Blocks that differ between two devices, as a range of block numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DiffRange {
+    pub first: u64,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffReport {
+    pub granularity: u64,
+    pub size_a: u64,
+    pub size_b: u64,
+    pub total_blocks: u64,
+    pub changed_blocks: u64,
+    pub ranges: Vec<DiffRange>,
+}
+
+impl DiffReport {
+    pub fn is_identical(&self) -> bool {
+        self.size_a == self.size_b && self.ranges.is_empty()
+    }
+}
+
+/// Compares two raw files or block devices by blocks of `granularity` bytes.
+/// When the sizes differ the shorter one is compared as if it was padded
+/// with zeros.
+pub fn diff(a: &File, b: &File, granularity: u64) -> io::Result<DiffReport> {
+    if granularity == 0 || !granularity.is_multiple_of(512) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Granularity {} is not a multiple of 512", granularity),
+        ));
+    }
+
+    let size_a = device_size(a)?;
+    let size_b = device_size(b)?;
+    let end = size_a.max(size_b);
+
+    let mut report = DiffReport {
+        granularity,
+        size_a,
+        size_b,
+        total_blocks: end.div_ceil(granularity),
+        changed_blocks: 0,
+        ranges: Vec::new(),
+    };
+
+    let buf_len = DIFF_BUFFER_SIZE.next_multiple_of(granularity);
+    let mut buf_a = vec![0u8; buf_len as usize];
+    let mut buf_b = vec![0u8; buf_len as usize];
+    let mut offset = 0;
+
+    while offset < end {
+        let len = (end - offset).min(buf_len) as usize;
+        read_padded(a, &mut buf_a[..len], offset)?;
+        read_padded(b, &mut buf_b[..len], offset)?;
+
+        let blocks = buf_a[..len]
+            .chunks(granularity as usize)
+            .zip(buf_b[..len].chunks(granularity as usize));
+
+        for (i, (block_a, block_b)) in blocks.enumerate() {
+            if block_a == block_b {
+                continue;
+            }
+
+            let block = offset / granularity + i as u64;
+            report.changed_blocks += 1;
+            match report.ranges.last_mut() {
+                Some(range) if range.first + range.count == block => range.count += 1,
+                _ => report.ranges.push(DiffRange {
+                    first: block,
+                    count: 1,
+                }),
+            }
+        }
+
+        offset += len as u64;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+
+    use super::{DiffRange, DiffReport, diff};
+    use crate::testutil::TempFile;
+
+    fn diff_files(name: &str, a: &[u8], b: &[u8], granularity: u64) -> DiffReport {
+        let tmp_a = TempFile::new(&format!("{}-a", name));
+        let tmp_b = TempFile::new(&format!("{}-b", name));
+        fs::write(tmp_a.path(), a).unwrap();
+        fs::write(tmp_b.path(), b).unwrap();
+        let file_a = File::open(tmp_a.path()).unwrap();
+        let file_b = File::open(tmp_b.path()).unwrap();
+        diff(&file_a, &file_b, granularity).unwrap()
+    }
+
+    #[test]
+    fn diff_reports_changed_ranges() {
+        let a: Vec<u8> = (0..3 << 20).map(|i| (i % 251) as u8).collect();
+        let report = diff_files("diff-ranges", &a, &a, 4096);
+        assert!(report.is_identical());
+        assert_eq!(report.total_blocks, 768);
+
+        // The second range spans two read buffers and is merged
+        let mut b = a.clone();
+        b[5000] ^= 1;
+        b[8191] ^= 1;
+        b[(1 << 20) - 1] ^= 1;
+        b[1 << 20] ^= 1;
+        b[(1 << 20) + 4096] ^= 1;
+        let report = diff_files("diff-ranges", &a, &b, 4096);
+        assert!(!report.is_identical());
+        assert_eq!(report.changed_blocks, 4);
+        assert_eq!(
+            report.ranges,
+            [
+                DiffRange { first: 1, count: 1 },
+                DiffRange {
+                    first: 255,
+                    count: 3
+                }
+            ]
+        );
+
+        // With a coarser granularity the blocks are larger
+        let report = diff_files("diff-ranges", &a, &b, 1 << 20);
+        assert_eq!(report.ranges, [DiffRange { first: 0, count: 2 }]);
+    }
+
+    #[test]
+    fn diff_pads_the_shorter_file_with_zeros() {
+        let a = vec![1u8; 8192];
+        let mut b = a.clone();
+        b.resize(16384, 0);
+        let report = diff_files("diff-padded", &a, &b, 512);
+        assert!(!report.is_identical());
+        assert!(report.ranges.is_empty());
+        assert_eq!((report.size_a, report.size_b), (8192, 16384));
+        assert_eq!(report.total_blocks, 32);
+
+        // A partial last block is compared too
+        b.truncate(10000);
+        b[9999] = 2;
+        let report = diff_files("diff-padded", &a, &b, 4096);
+        assert_eq!(report.ranges, [DiffRange { first: 2, count: 1 }]);
+        assert_eq!(report.total_blocks, 3);
+    }
+
+    #[test]
+    fn diff_granularity_is_a_multiple_of_512() {
+        let tmp = TempFile::new("diff-granularity");
+        fs::write(tmp.path(), [0u8; 512]).unwrap();
+        let file = File::open(tmp.path()).unwrap();
+        assert!(diff(&file, &file, 0).is_err());
+        assert!(diff(&file, &file, 1000).is_err());
+        assert!(diff(&file, &file, 1024).unwrap().is_identical());
+    }
+}
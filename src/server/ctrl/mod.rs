@@ -6,12 +6,12 @@ use serde_json::json;
 use std::{
     io::{Read, Write},
     net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    sync::{Arc, RwLock},
 };
 
 use crate::qcow2::Qcow2;
 
-pub fn start_ctrl_server(qcow: Arc<Mutex<Qcow2>>) {
+pub fn start_ctrl_server(qcow: Arc<RwLock<Qcow2>>) {
     info!("Starting controller on localhost:1234");
     info!("  > ctrl-c to quit, ");
     let help =
@@ -45,7 +45,7 @@ struct JsonRpcRequest {
     params: serde_json::Value,
 }
 
-fn handle_connection(mut stream: TcpStream, qcow: Arc<Mutex<Qcow2>>) {
+fn handle_connection(mut stream: TcpStream, qcow: Arc<RwLock<Qcow2>>) {
     let rpc_methods = rpc_methods::init_once();
 
     let parse_error = json!({
@@ -104,11 +104,24 @@ fn handle_connection(mut stream: TcpStream, qcow: Arc<Mutex<Qcow2>>) {
     let _ = request.jsonrpc;
 
     let response = if let Some(handler) = rpc_methods.get(request.method.as_str()) {
-        json!({
-            "jsonrpc": "2.0",
-            "result": handler(&qcow, &request.params),
-            "id":request.id,
-        })
+        match handler(&qcow, &request.params) {
+            Ok(result) => json!({
+                "jsonrpc": "2.0",
+                "result": result,
+                "id":request.id,
+            }),
+            Err(e) => {
+                error!("{}: {}", request.method, e.message);
+                json!({
+                    "jsonrpc": "2.0",
+                    "error": {
+                        "code": e.code,
+                        "message": e.message
+                    },
+                    "id": request.id
+                })
+            }
+        }
     } else {
         json!({
             "jsonrpc": "2.0",
@@ -1,53 +1,208 @@
 use base64::{Engine as _, engine::general_purpose};
-use log::warn;
+use log::{error, warn};
 use serde_json::json;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::{Arc, OnceLock, RwLock};
 
 use crate::qcow2::Qcow2;
 
-type RpcHandler = fn(&Arc<Mutex<Qcow2>>, &serde_json::Value) -> serde_json::Value;
+// Largest range returned by a single read, the data is held in memory and
+// encoded while the image is locked.
+const MAX_READ_LENGTH: u64 = 4 << 20;
+
+// https://www.jsonrpc.org/specification#error_object
+#[derive(Debug)]
+pub(super) struct RpcError {
+    pub(super) code: i64,
+    pub(super) message: String,
+}
+
+impl RpcError {
+    fn invalid_params(message: impl Into<String>) -> Self {
+        RpcError {
+            code: -32602,
+            message: message.into(),
+        }
+    }
+
+    // The method failed on the image
+    fn internal(message: impl Into<String>) -> Self {
+        RpcError {
+            code: -32603,
+            message: message.into(),
+        }
+    }
+}
+
+type RpcResult = Result<serde_json::Value, RpcError>;
+type RpcHandler = fn(&Arc<RwLock<Qcow2>>, &serde_json::Value) -> RpcResult;
 static RPC_METHODS: OnceLock<HashMap<&'static str, RpcHandler>> = OnceLock::new();
 
-fn rpc_cluster_size(qcow: &Arc<Mutex<Qcow2>>, _params: &serde_json::Value) -> serde_json::Value {
-    let mut q = qcow.lock().unwrap();
-    json!(q.cluster_size())
+fn rpc_check(qcow: &Arc<RwLock<Qcow2>>, _params: &serde_json::Value) -> RpcResult {
+    let q = qcow.read().unwrap();
+    q.check()
+        .map(|report| json!(report))
+        .map_err(|e| RpcError::internal(format!("Failed to check image: {}", e)))
+}
+
+fn rpc_cluster_size(qcow: &Arc<RwLock<Qcow2>>, _params: &serde_json::Value) -> RpcResult {
+    let q = qcow.read().unwrap();
+    Ok(json!(q.cluster_size()))
 }
 
-fn rpc_get_backing_file(
-    qcow: &Arc<Mutex<Qcow2>>,
-    _params: &serde_json::Value,
-) -> serde_json::Value {
-    let mut q = qcow.lock().unwrap();
+fn rpc_get_backing_file(qcow: &Arc<RwLock<Qcow2>>, _params: &serde_json::Value) -> RpcResult {
+    let q = qcow.read().unwrap();
     match q.backing_file() {
-        None => json!("".to_string()),
-        Some(s) => json!(s),
+        None => Ok(json!("".to_string())),
+        Some(s) => Ok(json!(s)),
     }
 }
 
-fn rpc_l1_size(qcow: &Arc<Mutex<Qcow2>>, _params: &serde_json::Value) -> serde_json::Value {
-    let mut q = qcow.lock().unwrap();
-    json!(q.l1_size())
+fn rpc_header_extensions(qcow: &Arc<RwLock<Qcow2>>, _params: &serde_json::Value) -> RpcResult {
+    let q = qcow.read().unwrap();
+    Ok(json!(q.header_extensions()))
 }
 
-fn rpc_l1_table_offset(qcow: &Arc<Mutex<Qcow2>>, _params: &serde_json::Value) -> serde_json::Value {
-    let mut q = qcow.lock().unwrap();
-    json!(q.l1_table_offset())
+fn rpc_l1_size(qcow: &Arc<RwLock<Qcow2>>, _params: &serde_json::Value) -> RpcResult {
+    let q = qcow.read().unwrap();
+    Ok(json!(q.l1_size()))
 }
 
-fn rpc_ping(_qcow: &Arc<Mutex<Qcow2>>, _params: &serde_json::Value) -> serde_json::Value {
-    json!("pong")
+fn rpc_l1_table_offset(qcow: &Arc<RwLock<Qcow2>>, _params: &serde_json::Value) -> RpcResult {
+    let q = qcow.read().unwrap();
+    Ok(json!(q.l1_table_offset()))
 }
 
-fn rpc_version(qcow: &Arc<Mutex<Qcow2>>, _params: &serde_json::Value) -> serde_json::Value {
-    let q = qcow.lock().unwrap();
-    json!(q.version())
+fn rpc_refcount(qcow: &Arc<RwLock<Qcow2>>, params: &serde_json::Value) -> RpcResult {
+    let cluster_index = match params.get("cluster") {
+        Some(v) => v.as_u64().unwrap_or_else(|| {
+            warn!("Failed to get cluster index, default to 0");
+            0
+        }),
+        None => {
+            warn!("No cluster index passed as parameter, default to 0");
+            0
+        }
+    };
+
+    let q = qcow.read().unwrap();
+    match q.get_refcount(cluster_index) {
+        Ok(refcount) => Ok(json!(refcount)),
+        Err(e) => {
+            error!("Failed to get refcount of cluster {}: {}", cluster_index, e);
+            Ok(json!(null))
+        }
+    }
 }
 
-fn rpc_read_guest_cluster(
-    qcow: &Arc<Mutex<Qcow2>>,
-    params: &serde_json::Value,
-) -> serde_json::Value {
+fn rpc_list_snapshots(qcow: &Arc<RwLock<Qcow2>>, _params: &serde_json::Value) -> RpcResult {
+    let q = qcow.read().unwrap();
+    q.snapshots()
+        .map(|snapshots| json!(snapshots))
+        .map_err(|e| RpcError::internal(format!("Failed to read snapshot table: {}", e)))
+}
+
+fn rpc_ping(_qcow: &Arc<RwLock<Qcow2>>, _params: &serde_json::Value) -> RpcResult {
+    Ok(json!("pong"))
+}
+
+fn rpc_snapshot(qcow: &Arc<RwLock<Qcow2>>, params: &serde_json::Value) -> RpcResult {
+    let action = params.get("action").and_then(|v| v.as_str());
+    let name = params
+        .get("name")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| RpcError::invalid_params("No snapshot name passed as parameter"))?;
+
+    let mut q = qcow.write().unwrap();
+    let result = match action {
+        Some("create") => q.create_snapshot(name).map(|snapshot| json!(snapshot)),
+        Some("apply") => q.apply_snapshot(name).map(|_| json!(true)),
+        Some("delete") => q.delete_snapshot(name).map(|_| json!(true)),
+        _ => {
+            return Err(RpcError::invalid_params(format!(
+                "Unknown snapshot action {:?}",
+                action
+            )));
+        }
+    };
+
+    result.map_err(|e| {
+        RpcError::internal(format!(
+            "Failed to {} snapshot {}: {}",
+            action.unwrap(),
+            name,
+            e
+        ))
+    })
+}
+
+fn rpc_resize(qcow: &Arc<RwLock<Qcow2>>, params: &serde_json::Value) -> RpcResult {
+    let size = params
+        .get("size")
+        .and_then(|v| v.as_u64())
+        .ok_or_else(|| RpcError::invalid_params("No size passed as parameter"))?;
+    let shrink = params
+        .get("shrink")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    let mut q = qcow.write().unwrap();
+    q.resize(size, shrink)
+        .map(|_| json!(q.virtual_size()))
+        .map_err(|e| RpcError::internal(format!("Failed to resize image to {}: {}", size, e)))
+}
+
+// Returns the offset and length of a guest range, they are both mandatory
+fn range_params(params: &serde_json::Value) -> Result<(u64, u64), RpcError> {
+    match (
+        params.get("offset").and_then(|v| v.as_u64()),
+        params.get("length").and_then(|v| v.as_u64()),
+    ) {
+        (Some(offset), Some(length)) => Ok((offset, length)),
+        _ => Err(RpcError::invalid_params(
+            "Offset and length must be passed as parameters",
+        )),
+    }
+}
+
+fn rpc_discard(qcow: &Arc<RwLock<Qcow2>>, params: &serde_json::Value) -> RpcResult {
+    let (offset, length) = range_params(params)?;
+
+    let mut q = qcow.write().unwrap();
+    q.discard(offset, length)
+        .map(|clusters| json!(clusters))
+        .map_err(|e| {
+            RpcError::internal(format!(
+                "Failed to discard {} bytes at {}: {}",
+                length, offset, e
+            ))
+        })
+}
+
+fn rpc_write_zeroes(qcow: &Arc<RwLock<Qcow2>>, params: &serde_json::Value) -> RpcResult {
+    let (offset, length) = range_params(params)?;
+    let may_unmap = params
+        .get("may_unmap")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    let mut q = qcow.write().unwrap();
+    q.write_zeroes(offset, length, may_unmap)
+        .map(|_| json!(true))
+        .map_err(|e| {
+            RpcError::internal(format!(
+                "Failed to write zeroes on {} bytes at {}: {}",
+                length, offset, e
+            ))
+        })
+}
+
+fn rpc_version(qcow: &Arc<RwLock<Qcow2>>, _params: &serde_json::Value) -> RpcResult {
+    let q = qcow.read().unwrap();
+    Ok(json!(q.version()))
+}
+
+fn rpc_read_guest_cluster(qcow: &Arc<RwLock<Qcow2>>, params: &serde_json::Value) -> RpcResult {
     let cluster_index = match params.get("cluster") {
         Some(v) => v.as_u64().unwrap_or_else(|| {
             // let's default to 0 for now
@@ -60,10 +215,34 @@ fn rpc_read_guest_cluster(
         }
     };
 
-    let mut q = qcow.lock().unwrap();
-    let data = q.read_guest_cluster(cluster_index);
-    let encoded = general_purpose::STANDARD.encode(data);
-    json!(encoded)
+    let q = qcow.read().unwrap();
+    match q.read_guest_cluster(cluster_index) {
+        Ok(data) => Ok(json!(general_purpose::STANDARD.encode(data))),
+        Err(e) => {
+            error!("Failed to read guest cluster {}: {}", cluster_index, e);
+            Ok(json!(null))
+        }
+    }
+}
+
+fn rpc_read(qcow: &Arc<RwLock<Qcow2>>, params: &serde_json::Value) -> RpcResult {
+    let (offset, length) = range_params(params)?;
+    if length > MAX_READ_LENGTH {
+        return Err(RpcError::invalid_params(format!(
+            "Length {} is larger than the maximum of {} bytes",
+            length, MAX_READ_LENGTH
+        )));
+    }
+
+    let q = qcow.read().unwrap();
+    q.read_at(offset, length as usize)
+        .map(|data| json!(general_purpose::STANDARD.encode(data)))
+        .map_err(|e| {
+            RpcError::internal(format!(
+                "Failed to read {} bytes at {}: {}",
+                length, offset, e
+            ))
+        })
 }
 
 // Method to list all available methods (RPC discover)
@@ -75,11 +254,17 @@ struct RpcMethodInfo {
     return_type: &'static str, // Return type as a string for simplicity (e.g., "string", "integer", etc.)
 }
 
-fn rpc_discover(_qcow: &Arc<Mutex<Qcow2>>, _params: &serde_json::Value) -> serde_json::Value {
+fn rpc_discover(_qcow: &Arc<RwLock<Qcow2>>, _params: &serde_json::Value) -> RpcResult {
     let methods = init_once();
     let method_infos: Vec<RpcMethodInfo> = methods
         .keys()
         .map(|&method_name| match method_name {
+            "check" => RpcMethodInfo {
+                name: method_name,
+                description: "Check the consistency of the image metadata",
+                params: vec![],
+                return_type: "check report object",
+            },
             "cluster_size" => RpcMethodInfo {
                 name: method_name,
                 description: "Cluster size",
@@ -92,12 +277,24 @@ fn rpc_discover(_qcow: &Arc<Mutex<Qcow2>>, _params: &serde_json::Value) -> serde
                 params: vec![],
                 return_type: "array of methods info objects",
             },
+            "discard" => RpcMethodInfo {
+                name: method_name,
+                description: "Deallocate the guest clusters entirely in the range",
+                params: vec![("offset", "integer"), ("length", "integer")],
+                return_type: "integer",
+            },
             "get_backing_file" => RpcMethodInfo {
                 name: method_name,
                 description: "Get backing file name",
                 params: vec![],
                 return_type: "string",
             },
+            "header_extensions" => RpcMethodInfo {
+                name: method_name,
+                description: "List header extensions",
+                params: vec![],
+                return_type: "array of header extensions",
+            },
             "l1_size" => RpcMethodInfo {
                 name: method_name,
                 description: "Number of entries in L1 table",
@@ -110,24 +307,64 @@ fn rpc_discover(_qcow: &Arc<Mutex<Qcow2>>, _params: &serde_json::Value) -> serde
                 params: vec![],
                 return_type: "integer (64-bit)",
             },
+            "list_snapshots" => RpcMethodInfo {
+                name: method_name,
+                description: "List internal snapshots",
+                params: vec![],
+                return_type: "array of snapshot objects",
+            },
             "ping" => RpcMethodInfo {
                 name: method_name,
                 description: "Ping, check if server is running",
                 params: vec![],
                 return_type: "string",
             },
+            "read" => RpcMethodInfo {
+                name: method_name,
+                description: "Read guest data, stops at the virtual size (4 MiB at most)",
+                params: vec![("offset", "integer"), ("length", "integer")],
+                return_type: "Base64 encoded string",
+            },
             "read_guest_cluster" => RpcMethodInfo {
                 name: method_name,
                 description: "Read guest cluster",
                 params: vec![("cluster", "integer")],
                 return_type: "Base64 encoded string",
             },
+            "refcount" => RpcMethodInfo {
+                name: method_name,
+                description: "Refcount of a host cluster",
+                params: vec![("cluster", "integer")],
+                return_type: "integer",
+            },
+            "resize" => RpcMethodInfo {
+                name: method_name,
+                description: "Change the virtual size, shrink discards data beyond the new size",
+                params: vec![("size", "integer"), ("shrink", "boolean (optional)")],
+                return_type: "integer",
+            },
+            "snapshot" => RpcMethodInfo {
+                name: method_name,
+                description: "Create, apply or delete an internal snapshot",
+                params: vec![("action", "create|apply|delete"), ("name", "string")],
+                return_type: "snapshot object for create, true otherwise",
+            },
             "version" => RpcMethodInfo {
                 name: method_name,
                 description: "Version of the qcow2 file",
                 params: vec![],
                 return_type: "integer",
             },
+            "write_zeroes" => RpcMethodInfo {
+                name: method_name,
+                description: "Make a guest range read as zeros, may_unmap frees the host clusters",
+                params: vec![
+                    ("offset", "integer"),
+                    ("length", "integer"),
+                    ("may_unmap", "boolean (optional)"),
+                ],
+                return_type: "boolean",
+            },
             _ => RpcMethodInfo {
                 name: method_name,
                 description: "Unknown method",
@@ -137,20 +374,29 @@ fn rpc_discover(_qcow: &Arc<Mutex<Qcow2>>, _params: &serde_json::Value) -> serde
         })
         .collect();
 
-    json!(method_infos)
+    Ok(json!(method_infos))
 }
 
 pub fn init_once() -> &'static HashMap<&'static str, RpcHandler> {
     RPC_METHODS.get_or_init(|| {
         let mut map: HashMap<&'static str, RpcHandler> = HashMap::new();
+        map.insert("check", rpc_check as RpcHandler);
         map.insert("cluster_size", rpc_cluster_size as RpcHandler);
+        map.insert("discard", rpc_discard as RpcHandler);
         map.insert("discover", rpc_discover as RpcHandler);
         map.insert("get_backing_file", rpc_get_backing_file as RpcHandler);
+        map.insert("header_extensions", rpc_header_extensions as RpcHandler);
         map.insert("l1_size", rpc_l1_size as RpcHandler);
         map.insert("l1_table_offset", rpc_l1_table_offset as RpcHandler);
+        map.insert("list_snapshots", rpc_list_snapshots as RpcHandler);
         map.insert("ping", rpc_ping as RpcHandler);
+        map.insert("read", rpc_read as RpcHandler);
         map.insert("read_guest_cluster", rpc_read_guest_cluster as RpcHandler);
+        map.insert("refcount", rpc_refcount as RpcHandler);
+        map.insert("resize", rpc_resize as RpcHandler);
+        map.insert("snapshot", rpc_snapshot as RpcHandler);
         map.insert("version", rpc_version as RpcHandler);
+        map.insert("write_zeroes", rpc_write_zeroes as RpcHandler);
         map
     })
 }
@@ -6,14 +6,26 @@ use nbd::start_nbd_server;
 
 use crate::qcow2::Qcow2;
 
-use log::debug;
-use std::sync::{Arc, Mutex};
+use log::{debug, error};
+use std::sync::{Arc, RwLock};
 use std::thread;
 
-pub fn start_servers(fname: &str) {
-    let qcow = Arc::new(Mutex::new(
-        Qcow2::new(fname).expect("Failed to read qcow file"),
-    ));
+/// Opens the image and starts the servers on it. The image is only opened
+/// read-write when `writable` is set, methods that modify it fail otherwise.
+pub fn start_servers(fname: &str, writable: bool) {
+    match Qcow2::open(fname, writable) {
+        Ok(qcow) => serve(qcow),
+        Err(e) => {
+            error!("Failed to open {}: {}", fname, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Starts the servers on an image that is already opened, for example a
+/// read-only view at a snapshot.
+pub fn serve(qcow: Qcow2) {
+    let qcow = Arc::new(RwLock::new(qcow));
 
     debug!("Starting NBD server");
     let qcow_clone = Arc::clone(&qcow);
@@ -1,7 +1,7 @@
 use log::{debug, error, info};
 use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
 
 use crate::qcow2::Qcow2;
 
@@ -47,7 +47,7 @@ impl TryFrom<u32> for NbdOpt {
 //     NbdRepMetaContext,
 // }
 
-pub fn start_nbd_server(_qcow: Arc<Mutex<Qcow2>>) {
+pub fn start_nbd_server(_qcow: Arc<RwLock<Qcow2>>) {
     info!("Starting nbd server on localhost:10809");
     info!("  > ctrl-c to quit, ");
 
@@ -0,0 +1,24 @@
+use std::fs;
+use std::path::PathBuf;
+
+// Scratch file of a test, it is removed when dropped. The name is made unique
+// by the process id as tests run in parallel.
+pub(crate) struct TempFile(PathBuf);
+
+impl TempFile {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rblock-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        TempFile(path)
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}